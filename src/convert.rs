@@ -0,0 +1,721 @@
+//! The note → Program Change mapping, kept free of plugin state so it can be
+//! reasoned about in isolation.
+
+use crate::scale::{white_key_index, WHITE_KEY_COUNT};
+use nih_plug::prelude::Enum;
+
+/// Parameter values that drive the note → Program Change conversion,
+/// snapshotted once per buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct ConvertSettings {
+    /// 0 = follow the incoming channel, 1–16 = force that channel.
+    pub output_channel: u8,
+    /// Lowest note (inclusive) that gets converted.
+    pub min_note: u8,
+    /// Highest note (inclusive) that gets converted.
+    pub max_note: u8,
+    /// A learned (lowest, highest) range that replaces `min_note` and
+    /// `max_note` and is spread evenly over programs 0–127.
+    pub learned_range: Option<(u8, u8)>,
+    /// Octaves added to the note before range gating and mapping.
+    pub octave_shift: i8,
+    /// Shifted notes are snapped to the nearest multiple of this many
+    /// semitones from the anchor, at least 1.
+    pub note_quantize_interval: u8,
+    /// The note that maps to program 0. Notes below it compute negative
+    /// programs, handled by `out_of_range_program`.
+    pub anchor_note: u8,
+    /// Number of adjacent notes sharing one program, at least 1.
+    pub band_size: u8,
+    /// Maps each octave's twelve notes to twelve programs, each octave up
+    /// adding this, in place of the bands.
+    pub program_offset_per_octave: Option<u8>,
+    /// Spreads the white keys evenly over programs 0–127 in place of the
+    /// bands, skipping black keys.
+    pub white_keys_only: bool,
+    /// Program every in-range note selects, overriding the mapping.
+    pub fixed_program: Option<u8>,
+    /// Final remap of the computed program, indexed by program.
+    pub program_map: [u8; 128],
+    /// Per-note overrides of the program and channel, indexed by note.
+    pub note_map: [Option<NoteMapEntry>; 128],
+    /// The final program is rounded down to a multiple of this, at least 1.
+    pub program_step: u8,
+    /// What to do with computed programs outside 0–127.
+    pub out_of_range_program: OutOfRangeProgram,
+    /// Number of patches on the target device, 1–128. Final programs are
+    /// brought below it by `out_of_range_program`.
+    pub patch_count: u8,
+}
+
+/// A note's entry in the note map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoteMapEntry {
+    /// 1–16 sends the program on that channel, 0 follows `output_channel`.
+    pub channel: u8,
+    pub program: u8,
+}
+
+/// How a computed program outside 0–127 is brought back into range.
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfRangeProgram {
+    /// Drop the note, no Program Change is sent.
+    #[name = "Skip"]
+    Skip,
+    /// Use the nearest valid program, 0 or 127.
+    #[name = "Clamp"]
+    Clamp,
+    /// Wrap around, so 128 becomes 0 and -1 becomes 127.
+    #[name = "Wrap"]
+    Wrap,
+}
+
+/// Brings `program` into `0..patch_count` according to `policy`, or `None`
+/// if it is skipped. `patch_count` is at most 128.
+pub fn fit_program(program: i16, patch_count: u8, policy: OutOfRangeProgram) -> Option<u8> {
+    let count = patch_count.clamp(1, 128) as i16;
+    match policy {
+        OutOfRangeProgram::Skip => u8::try_from(program).ok().filter(|&p| (p as i16) < count),
+        OutOfRangeProgram::Clamp => Some(program.clamp(0, count - 1) as u8),
+        OutOfRangeProgram::Wrap => Some(program.rem_euclid(count) as u8),
+    }
+}
+
+/// A Program Change resolved from an incoming note, channel is 0-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramChange {
+    pub channel: u8,
+    pub program: u8,
+    /// Bank Select MSB (CC 0) sent ahead of the program, if any.
+    pub bank_msb: Option<u8>,
+    /// Bank Select LSB (CC 32) sent ahead of the program, if any.
+    pub bank_lsb: Option<u8>,
+    /// A CC number and 7-bit value sent right after the program, if any.
+    pub follow_cc: Option<(u8, u8)>,
+    /// Mod wheel value to send with the program as an expression patch
+    /// SysEx, in place of the Program Change, if any.
+    pub mod_wheel: Option<u8>,
+    /// Note whose name is sent as a text SysEx after the program, if any.
+    pub note_name: Option<u8>,
+}
+
+impl ProgramChange {
+    /// A Program Change without a Bank Select or following CC.
+    pub fn new(channel: u8, program: u8) -> Self {
+        Self {
+            channel,
+            program,
+            bank_msb: None,
+            bank_lsb: None,
+            follow_cc: None,
+            mod_wheel: None,
+            note_name: None,
+        }
+    }
+}
+
+/// The 7-bit value a note maps to for the note CC: `note * scale + offset`,
+/// clamped to 0–127.
+pub fn note_cc_value(note: u8, scale: f32, offset: i32) -> u8 {
+    (note as f32 * scale + offset as f32).round().clamp(0.0, 127.0) as u8
+}
+
+/// Resolves the output channel: 0 keeps the incoming channel, 1–16 force
+/// that channel (the user picks 1–16, nih-plug uses 0–15). Always returns a
+/// valid channel.
+pub fn resolve_channel(output_channel: u8, channel: u8) -> u8 {
+    if output_channel == 0 {
+        channel.min(15)
+    } else {
+        (output_channel - 1).min(15)
+    }
+}
+
+/// Maps an incoming channel to nih-plug's 0–15, undoing the shift of hosts
+/// that send 1–16 when `one_based` is set.
+pub fn normalize_input_channel(channel: u8, one_based: bool) -> u8 {
+    if one_based {
+        channel.saturating_sub(1).min(15)
+    } else {
+        channel
+    }
+}
+
+/// Shifts `note` by `octave_shift` octaves, or `None` if that leaves the
+/// MIDI note range.
+pub fn shift_note(note: u8, octave_shift: i8) -> Option<u8> {
+    let note = note as i16 + octave_shift as i16 * 12;
+    u8::try_from(note).ok().filter(|&note| note <= 127)
+}
+
+/// Snaps `note` to the nearest multiple of `interval` semitones from
+/// `anchor`, halfway rounding up. A snap past note 127 goes one interval
+/// down instead.
+pub fn quantize_note(note: u8, anchor: u8, interval: u8) -> u8 {
+    let interval = interval.max(1) as i16;
+    let anchor = anchor as i16;
+    let steps = (note as i16 - anchor + interval / 2).div_euclid(interval);
+    let note = anchor + steps * interval;
+    let note = if note > 127 { note - interval } else { note };
+    note.clamp(0, 127) as u8
+}
+
+/// The note the range gate and the mapping see for an incoming `note`:
+/// shifted by the octave shift and then quantized, or `None` if the shift
+/// goes past either end of the MIDI range.
+pub fn gated_note(settings: &ConvertSettings, note: u8) -> Option<u8> {
+    let note = shift_note(note, settings.octave_shift)?;
+    Some(quantize_note(
+        note,
+        settings.anchor_note,
+        settings.note_quantize_interval,
+    ))
+}
+
+/// Converts a note-on into the Program Change it should produce, or `None`
+/// if the note is outside the configured range. Both ends of the range are
+/// inclusive: `min_note` and `max_note` themselves convert.
+///
+/// The octave shift is applied first, so the range gate and the mapping both
+/// see the shifted note. Shifting past either end of the MIDI range counts as
+/// out of range. The shifted note is then snapped to
+/// `note_quantize_interval`, to soak up jittery controllers. Notes are
+/// counted from the anchor and then grouped into bands of `band_size`
+/// notes, or with `program_offset_per_octave` set, select their pitch class
+/// plus that offset per octave: with an offset of 16, the second octave
+/// starts at program 16. In `white_keys_only` mode black keys are skipped,
+/// and the 75 white keys are spread evenly over programs 0–127 instead.
+/// A `learned_range` takes the place of the note range, and its notes are
+/// spread over programs 0–127, so playing the learned range reaches every
+/// program. With a `fixed_program` set, every note that passes the range gate
+/// selects it. Notes in the `note_map` instead select their
+/// entry's program, on their entry's channel if it has one. The resulting
+/// program is then looked up in `program_map` and rounded down to a
+/// multiple of `program_step`, so a program of 5 with a step of 2 sends 4.
+///
+/// Programs that fall outside 0–127 along the way, like those of notes below
+/// the anchor, are handled by `out_of_range_program` at each step, and so
+/// are final programs the device doesn't have, per `patch_count`.
+///
+/// This runs on the audio thread, so it must not panic for any input: every
+/// step uses checked arithmetic, skipping the note (`None`) where a result
+/// would be invalid, and any returned program is within 0–127.
+pub fn convert_note(settings: &ConvertSettings, channel: u8, note: u8) -> Option<ProgramChange> {
+    let note = gated_note(settings, note)?;
+
+    // Only convert notes within the configured range
+    let (min_note, max_note) = settings
+        .learned_range
+        .unwrap_or((settings.min_note, settings.max_note));
+    if !(min_note..=max_note).contains(&note) {
+        return None;
+    }
+
+    let white_key = white_key_index(note);
+    if settings.white_keys_only && white_key.is_none() {
+        return None;
+    }
+
+    let entry = settings.note_map[note as usize];
+    let policy = settings.out_of_range_program;
+    let program = match (entry, settings.fixed_program, settings.learned_range) {
+        (Some(entry), _, _) => entry.program as i16,
+        (None, Some(program), _) => program as i16,
+        (None, None, Some(_)) => {
+            // Rounded like the white keys, a single learned note selects 0
+            let span = (max_note - min_note) as i16;
+            match span {
+                0 => 0,
+                _ => ((note - min_note) as i16 * 127 + span / 2) / span,
+            }
+        }
+        (None, None, None) if settings.white_keys_only => {
+            // Rounded, so both ends of the keyboard reach 0 and 127
+            let last = (WHITE_KEY_COUNT - 1) as i16;
+            (white_key.unwrap_or(0) as i16 * 127 + last / 2) / last
+        }
+        (None, None, None) => {
+            let note = note as i16 - settings.anchor_note as i16;
+            match settings.program_offset_per_octave {
+                Some(offset) => note.rem_euclid(12) + note.div_euclid(12) * offset as i16,
+                None => note.div_euclid(settings.band_size.max(1) as i16),
+            }
+        }
+    };
+    let program = fit_program(program, 128, policy)?;
+    let program = fit_program(
+        settings.program_map[program as usize] as i16,
+        settings.patch_count,
+        policy,
+    )?;
+    let program = program - program % settings.program_step.max(1);
+
+    let output_channel = match entry {
+        Some(entry) if entry.channel != 0 => entry.channel,
+        _ => settings.output_channel,
+    };
+    Some(ProgramChange::new(
+        resolve_channel(output_channel, channel),
+        program,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every note converts to its own program on the incoming channel.
+    fn settings() -> ConvertSettings {
+        ConvertSettings {
+            output_channel: 0,
+            min_note: 0,
+            max_note: 127,
+            learned_range: None,
+            octave_shift: 0,
+            note_quantize_interval: 1,
+            anchor_note: 0,
+            band_size: 1,
+            program_offset_per_octave: None,
+            white_keys_only: false,
+            fixed_program: None,
+            program_map: std::array::from_fn(|program| program as u8),
+            note_map: [None; 128],
+            program_step: 1,
+            out_of_range_program: OutOfRangeProgram::Skip,
+            patch_count: 128,
+        }
+    }
+
+    #[test]
+    fn follow_mode_keeps_the_incoming_channel() {
+        for channel in 0..16 {
+            assert_eq!(resolve_channel(0, channel), channel);
+            let pc = convert_note(&settings(), channel, 60).unwrap();
+            assert_eq!(pc, ProgramChange::new(channel, 60));
+        }
+    }
+
+    #[test]
+    fn forced_channel_1_is_channel_0() {
+        let settings = ConvertSettings {
+            output_channel: 1,
+            ..settings()
+        };
+        assert_eq!(resolve_channel(1, 9), 0);
+        assert_eq!(convert_note(&settings, 9, 60).unwrap().channel, 0);
+    }
+
+    #[test]
+    fn forced_channel_16_is_channel_15() {
+        let settings = ConvertSettings {
+            output_channel: 16,
+            ..settings()
+        };
+        assert_eq!(resolve_channel(16, 0), 15);
+        assert_eq!(convert_note(&settings, 0, 60).unwrap().channel, 15);
+    }
+
+    #[test]
+    fn notes_above_max_note_are_skipped() {
+        let settings = ConvertSettings {
+            max_note: 60,
+            ..settings()
+        };
+        for note in 61..=127 {
+            assert_eq!(convert_note(&settings, 0, note), None);
+        }
+    }
+
+    #[test]
+    fn range_bounds_are_inclusive() {
+        let settings = ConvertSettings {
+            min_note: 36,
+            max_note: 84,
+            ..settings()
+        };
+        assert_eq!(convert_note(&settings, 0, 36).unwrap().program, 36);
+        assert_eq!(convert_note(&settings, 0, 84).unwrap().program, 84);
+        assert_eq!(convert_note(&settings, 0, 35), None);
+        assert_eq!(convert_note(&settings, 0, 85), None);
+    }
+
+    #[test]
+    fn single_note_range_converts_that_note() {
+        let settings = ConvertSettings {
+            min_note: 60,
+            max_note: 60,
+            ..settings()
+        };
+        assert!(convert_note(&settings, 0, 60).is_some());
+        assert_eq!(convert_note(&settings, 0, 59), None);
+        assert_eq!(convert_note(&settings, 0, 61), None);
+    }
+
+    #[test]
+    fn shift_note_stays_in_range() {
+        for note in 0..=127 {
+            for octave_shift in -10..=10 {
+                if let Some(shifted) = shift_note(note, octave_shift) {
+                    assert!(shifted <= 127);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quantize_note_stays_in_range() {
+        for note in 0..=127 {
+            for anchor in 0..=127 {
+                for interval in [0, 1, 2, 12, 127, 255] {
+                    assert!(quantize_note(note, anchor, interval) <= 127);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fit_program_stays_in_range() {
+        let policies = [
+            OutOfRangeProgram::Skip,
+            OutOfRangeProgram::Clamp,
+            OutOfRangeProgram::Wrap,
+        ];
+        for program in i16::MIN..=i16::MAX {
+            for patch_count in [0, 1, 64, 128, 255] {
+                for policy in policies {
+                    if let Some(fitted) = fit_program(program, patch_count, policy) {
+                        assert!(fitted < patch_count.clamp(1, 128));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn convert_note_stays_in_range() {
+        let extremes = [
+            settings(),
+            ConvertSettings {
+                band_size: 0,
+                program_step: 0,
+                note_quantize_interval: 0,
+                ..settings()
+            },
+            ConvertSettings {
+                band_size: 255,
+                program_step: 255,
+                note_quantize_interval: 255,
+                ..settings()
+            },
+            ConvertSettings {
+                program_offset_per_octave: Some(0),
+                ..settings()
+            },
+            ConvertSettings {
+                program_offset_per_octave: Some(255),
+                ..settings()
+            },
+            ConvertSettings {
+                white_keys_only: true,
+                ..settings()
+            },
+            ConvertSettings {
+                fixed_program: Some(255),
+                ..settings()
+            },
+            ConvertSettings {
+                learned_range: Some((0, 0)),
+                ..settings()
+            },
+            ConvertSettings {
+                learned_range: Some((0, 127)),
+                ..settings()
+            },
+            ConvertSettings {
+                learned_range: Some((127, 0)),
+                ..settings()
+            },
+            ConvertSettings {
+                learned_range: Some((255, 255)),
+                ..settings()
+            },
+            ConvertSettings {
+                output_channel: 255,
+                program_map: [255; 128],
+                ..settings()
+            },
+            ConvertSettings {
+                note_map: [Some(NoteMapEntry {
+                    channel: 255,
+                    program: 255,
+                }); 128],
+                ..settings()
+            },
+        ];
+        let policies = [
+            OutOfRangeProgram::Skip,
+            OutOfRangeProgram::Clamp,
+            OutOfRangeProgram::Wrap,
+        ];
+        for extreme in extremes {
+            for anchor_note in [0, 127] {
+                for out_of_range_program in policies {
+                    for patch_count in [0, 1, 128, 255] {
+                        let settings = ConvertSettings {
+                            anchor_note,
+                            out_of_range_program,
+                            patch_count,
+                            ..extreme
+                        };
+                        for note in 0..=127 {
+                            for octave_shift in -10..=10 {
+                                let settings = ConvertSettings {
+                                    octave_shift,
+                                    ..settings
+                                };
+                                if let Some(pc) = convert_note(&settings, 15, note) {
+                                    assert!(pc.program <= 127);
+                                    assert!(pc.channel <= 15);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn one_based_input_channels_shift_down() {
+        assert_eq!(normalize_input_channel(1, true), 0);
+        assert_eq!(normalize_input_channel(16, true), 15);
+        // Out-of-spec values still land on a valid channel
+        assert_eq!(normalize_input_channel(0, true), 0);
+        assert_eq!(normalize_input_channel(200, true), 15);
+        assert_eq!(normalize_input_channel(0, false), 0);
+        assert_eq!(normalize_input_channel(15, false), 15);
+    }
+
+    #[test]
+    fn octave_shift_applies_before_the_range_gate() {
+        let settings = ConvertSettings {
+            min_note: 36,
+            max_note: 60,
+            octave_shift: 1,
+            ..settings()
+        };
+        assert_eq!(convert_note(&settings, 0, 48).unwrap().program, 60);
+        assert_eq!(convert_note(&settings, 0, 49), None);
+        assert_eq!(convert_note(&settings, 0, 24).unwrap().program, 36);
+        assert_eq!(convert_note(&settings, 0, 23), None);
+    }
+
+    #[test]
+    fn shifting_off_the_keyboard_skips_the_note() {
+        let up = ConvertSettings {
+            octave_shift: 2,
+            ..settings()
+        };
+        let down = ConvertSettings {
+            octave_shift: -2,
+            ..settings()
+        };
+        assert_eq!(convert_note(&up, 0, 104), None);
+        assert_eq!(convert_note(&down, 0, 23), None);
+        assert_eq!(convert_note(&down, 0, 24).unwrap().program, 0);
+    }
+
+    #[test]
+    fn notes_count_from_the_anchor() {
+        let settings = ConvertSettings {
+            anchor_note: 36,
+            ..settings()
+        };
+        assert_eq!(convert_note(&settings, 0, 36).unwrap().program, 0);
+        assert_eq!(convert_note(&settings, 0, 40).unwrap().program, 4);
+        assert_eq!(convert_note(&settings, 0, 127).unwrap().program, 91);
+        assert_eq!(convert_note(&settings, 0, 35), None);
+    }
+
+    #[test]
+    fn bands_share_one_program() {
+        let settings = ConvertSettings {
+            anchor_note: 36,
+            band_size: 4,
+            ..settings()
+        };
+        for note in 36..40 {
+            assert_eq!(convert_note(&settings, 0, note).unwrap().program, 0);
+        }
+        assert_eq!(convert_note(&settings, 0, 40).unwrap().program, 1);
+        assert_eq!(convert_note(&settings, 0, 127).unwrap().program, 22);
+    }
+
+    #[test]
+    fn fixed_program_ignores_the_note() {
+        let settings = ConvertSettings {
+            max_note: 99,
+            fixed_program: Some(42),
+            ..settings()
+        };
+        assert_eq!(convert_note(&settings, 0, 0).unwrap().program, 42);
+        assert_eq!(convert_note(&settings, 0, 99).unwrap().program, 42);
+        // The range gate still applies
+        assert_eq!(convert_note(&settings, 0, 100), None);
+    }
+
+    #[test]
+    fn notes_below_the_anchor_follow_the_out_of_range_policy() {
+        let with_policy = |out_of_range_program| ConvertSettings {
+            anchor_note: 60,
+            out_of_range_program,
+            ..settings()
+        };
+        let program = |settings: &ConvertSettings, note| {
+            convert_note(settings, 0, note).map(|pc| pc.program)
+        };
+
+        let skip = with_policy(OutOfRangeProgram::Skip);
+        assert_eq!(program(&skip, 59), None);
+        let clamp = with_policy(OutOfRangeProgram::Clamp);
+        assert_eq!(program(&clamp, 59), Some(0));
+        assert_eq!(program(&clamp, 0), Some(0));
+        let wrap = with_policy(OutOfRangeProgram::Wrap);
+        assert_eq!(program(&wrap, 59), Some(127));
+        assert_eq!(program(&wrap, 0), Some(68));
+    }
+
+    #[test]
+    fn programs_past_127_follow_the_out_of_range_policy() {
+        let with_policy = |out_of_range_program| ConvertSettings {
+            program_offset_per_octave: Some(127),
+            out_of_range_program,
+            ..settings()
+        };
+
+        // The second octave starts at program 127, C#0 lands on 128
+        let note = 13;
+        let skip = with_policy(OutOfRangeProgram::Skip);
+        assert_eq!(convert_note(&skip, 0, note), None);
+        let clamp = with_policy(OutOfRangeProgram::Clamp);
+        assert_eq!(convert_note(&clamp, 0, note).unwrap().program, 127);
+        let wrap = with_policy(OutOfRangeProgram::Wrap);
+        assert_eq!(convert_note(&wrap, 0, note).unwrap().program, 0);
+    }
+
+    #[test]
+    fn note_cc_value_scales_offsets_and_clamps() {
+        assert_eq!(note_cc_value(60, 1.0, 0), 60);
+        assert_eq!(note_cc_value(60, 0.5, 10), 40);
+        assert_eq!(note_cc_value(100, 2.0, 0), 127);
+        assert_eq!(note_cc_value(10, -1.0, 0), 0);
+        assert_eq!(note_cc_value(10, -1.0, 127), 117);
+    }
+
+    #[test]
+    fn program_step_rounds_down_after_the_map() {
+        let mut program_map = std::array::from_fn(|program| program as u8);
+        program_map[10] = 7;
+        let settings = ConvertSettings {
+            program_step: 4,
+            program_map,
+            ..settings()
+        };
+        assert_eq!(convert_note(&settings, 0, 5).unwrap().program, 4);
+        assert_eq!(convert_note(&settings, 0, 8).unwrap().program, 8);
+        assert_eq!(convert_note(&settings, 0, 10).unwrap().program, 4);
+        assert_eq!(convert_note(&settings, 0, 127).unwrap().program, 124);
+    }
+
+    #[test]
+    fn learned_range_spreads_over_every_program() {
+        let learned = ConvertSettings {
+            min_note: 0,
+            max_note: 10,
+            learned_range: Some((48, 72)),
+            ..settings()
+        };
+        assert_eq!(convert_note(&learned, 0, 48).unwrap().program, 0);
+        assert_eq!(convert_note(&learned, 0, 60).unwrap().program, 64);
+        assert_eq!(convert_note(&learned, 0, 72).unwrap().program, 127);
+        // It replaces the note range
+        assert_eq!(convert_note(&learned, 0, 5), None);
+        assert_eq!(convert_note(&learned, 0, 73), None);
+
+        let single = ConvertSettings {
+            learned_range: Some((60, 60)),
+            ..settings()
+        };
+        assert_eq!(convert_note(&single, 0, 60).unwrap().program, 0);
+    }
+
+    #[test]
+    fn octaves_add_their_program_offset() {
+        let settings = ConvertSettings {
+            program_offset_per_octave: Some(16),
+            ..settings()
+        };
+        assert_eq!(convert_note(&settings, 0, 11).unwrap().program, 11);
+        assert_eq!(convert_note(&settings, 0, 12).unwrap().program, 16);
+        assert_eq!(convert_note(&settings, 0, 27).unwrap().program, 35);
+
+        // Counted from the anchor, the octave below goes negative
+        let anchored = ConvertSettings {
+            anchor_note: 60,
+            ..settings
+        };
+        assert_eq!(convert_note(&anchored, 0, 72).unwrap().program, 16);
+        assert_eq!(convert_note(&anchored, 0, 59), None);
+    }
+
+    #[test]
+    fn programs_past_the_patch_count_follow_the_out_of_range_policy() {
+        let with_policy = |out_of_range_program| ConvertSettings {
+            patch_count: 32,
+            out_of_range_program,
+            ..settings()
+        };
+
+        let skip = with_policy(OutOfRangeProgram::Skip);
+        assert_eq!(convert_note(&skip, 0, 31).unwrap().program, 31);
+        assert_eq!(convert_note(&skip, 0, 32), None);
+        let clamp = with_policy(OutOfRangeProgram::Clamp);
+        assert_eq!(convert_note(&clamp, 0, 40).unwrap().program, 31);
+        let wrap = with_policy(OutOfRangeProgram::Wrap);
+        assert_eq!(convert_note(&wrap, 0, 40).unwrap().program, 8);
+    }
+
+    #[test]
+    fn notes_snap_to_the_interval_from_the_anchor() {
+        assert_eq!(quantize_note(61, 60, 4), 60);
+        assert_eq!(quantize_note(62, 60, 4), 64);
+        assert_eq!(quantize_note(59, 60, 4), 60);
+        assert_eq!(quantize_note(57, 60, 4), 56);
+        // A snap past 127 goes one interval down
+        assert_eq!(quantize_note(127, 0, 4), 124);
+
+        // The range gate sees the snapped note
+        let settings = ConvertSettings {
+            min_note: 60,
+            note_quantize_interval: 4,
+            ..settings()
+        };
+        assert_eq!(convert_note(&settings, 0, 58).unwrap().program, 60);
+        assert_eq!(convert_note(&settings, 0, 57), None);
+    }
+
+    #[test]
+    fn white_keys_spread_over_every_program() {
+        let settings = ConvertSettings {
+            white_keys_only: true,
+            ..settings()
+        };
+        assert_eq!(convert_note(&settings, 0, 0).unwrap().program, 0);
+        assert_eq!(convert_note(&settings, 0, 2).unwrap().program, 2);
+        assert_eq!(convert_note(&settings, 0, 60).unwrap().program, 60);
+        assert_eq!(convert_note(&settings, 0, 127).unwrap().program, 127);
+        // Black keys are skipped
+        assert_eq!(convert_note(&settings, 0, 1), None);
+        assert_eq!(convert_note(&settings, 0, 61), None);
+    }
+}
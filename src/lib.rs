@@ -1,5 +1,26 @@
+use convert::{
+    convert_note, fit_program, gated_note, normalize_input_channel, note_cc_value,
+    resolve_channel, ConvertSettings, NoteMapEntry, OutOfRangeProgram, ProgramChange,
+};
+use nih_plug::params::persist::PersistentField;
 use nih_plug::prelude::*;
-use std::sync::Arc;
+use note_set::NoteSet;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use sysex::{ExpressionPatch, NoteName, OutputSysEx};
+use transport::TransportGrid;
+
+mod convert;
+mod note_set;
+#[cfg(feature = "logging")]
+mod program_log;
+mod scale;
+#[cfg(feature = "logging")]
+mod smf;
+mod sysex;
+#[cfg(test)]
+mod tests;
+mod transport;
 
 /// A VST3/CLAP plugin that converts incoming MIDI note-on events into
 /// MIDI Program Change messages.
@@ -10,10 +31,194 @@ use std::sync::Arc;
 ///   D0  (note 2)  → Program Change 2
 ///   …up to note 99 (D#8) → Program Change 99
 ///
-/// Note-off events are silently consumed (Program Change has no "off").
-/// All other MIDI events (CCs, pitch bend, etc.) are passed through unchanged.
+/// Note-off events are consumed (Program Change has no "off"), optionally
+/// sending a release program. Out-of-range notes are consumed too unless
+/// forwarding is enabled. All other MIDI events (CCs, pitch bend, etc.) are
+/// passed through unchanged.
 struct MidiNoteToPc {
     params: Arc<MidiNoteToPcParams>,
+
+    /// Sample rate from the last `initialize()`, used for ms → samples.
+    sample_rate: f32,
+    /// Running sample position, advanced by the length of every buffer.
+    sample_clock: u64,
+    /// Samples processed since the last `initialize()`. Unlike
+    /// `sample_clock` this survives `reset()`.
+    samples_since_init: u64,
+    /// Program Changes scheduled for later samples, ordered by due time.
+    pending: Vec<PendingProgramChange>,
+    /// The transport's musical position for the current buffer, `None`
+    /// while stopped.
+    grid: Option<TransportGrid>,
+
+    /// The most recently sent Program Change.
+    last_pc: Option<ProgramChange>,
+    /// `output_channel` as seen by the previous buffer.
+    last_output_channel: Option<u8>,
+    /// Out-of-range notes that were passed through, so their note-offs
+    /// follow them even if the range changes while they are held.
+    forwarded_notes: NoteSet,
+    /// Notes whose note-on sent a Program Change. Only their note-offs send
+    /// a release program.
+    selecting_notes: NoteSet,
+    /// Bank picked by a bank-zone note in two-note mode, waiting for the
+    /// program note that sends it.
+    pending_bank: Option<u8>,
+    /// Number of notes currently held. Unmatched note-offs can't push it
+    /// below zero.
+    held_notes: u32,
+    /// Sample of the last note-on or note-off, or `None` once the idle
+    /// default program has been sent for it.
+    last_note_at: Option<u64>,
+    /// Channel of the note that started the current phrase, the first one
+    /// pressed with no other notes held.
+    phrase_channel: u8,
+    /// One bit per note that has already fired in once-per-note mode.
+    fired_notes: u128,
+    /// Programs sent per channel during the current buffer.
+    sent_this_buffer: NoteSet,
+    /// Last 7-bit value of the trigger CC per channel, for edge detection.
+    trigger_cc_values: [u8; 16],
+    /// Note-ons still to be dropped by the warm-up filter.
+    notes_to_ignore: u32,
+    /// Notes dropped by the warm-up filter, the held-note cap or because
+    /// they arm or disarm conversion, so their note-offs are dropped as well.
+    ignored_notes: NoteSet,
+    /// Last forwarded 7-bit value per channel and CC, `CC_UNSEEN` if none.
+    last_cc_values: [[u8; 128]; 16],
+    /// Held notes that converted, for top-note tracking.
+    top_notes: NoteSet,
+    /// The held note whose program is being re-sent, as (channel, note).
+    resend_note: Option<(u8, u8)>,
+    /// The held note aftertouch steps from in `pressure_selects_program`
+    /// mode.
+    pressure_base: Option<PressureBase>,
+    /// Sample clock of each note's last unpaired press in double-press
+    /// mode, `NO_PRESS` if none.
+    last_press: [u64; 128],
+    /// The beat the last Program Change went out in, for `one_per_beat`.
+    last_pc_beat: Option<i64>,
+    /// Program and position in beats of the last Program Change per
+    /// channel, for `dedupe_beats`.
+    last_pc_beats: [Option<(u8, f64)>; 16],
+    /// Sample clock of the last Program Change per channel, for the
+    /// minimum channel interval.
+    last_sent_at: [Option<u64>; 16],
+    /// The 1-based channel each held converted note also plays on, 0 if
+    /// none, so note-offs follow even if `split_note_channel` changes.
+    split_channels: [[u8; 128]; 16],
+    /// Audio-thread copy of the persisted program map.
+    program_map: [u8; 128],
+    /// Audio-thread copy of the persisted note map, indexed by note.
+    note_map: [Option<NoteMapEntry>; 128],
+    /// Set on an initialization that follows a state load, the first buffer
+    /// after it may recall the saved programs.
+    recall_pending: bool,
+    /// Audio-thread copy of `channel_programs`, written back whenever the
+    /// lock is free.
+    channel_programs: [Option<u8>; 16],
+    /// `channel_programs` has changes not yet written back.
+    channel_programs_dirty: bool,
+    /// `learn_range` as seen by the previous buffer.
+    learning: bool,
+    /// The latest converted program, waiting for the sustain pedal release.
+    pedal_pc: Option<ProgramChange>,
+    /// Velocity and note of the note-on behind the pending coalesced
+    /// program, for `CoalescePick::Loudest`.
+    coalesced_note: Option<(f32, u8)>,
+    /// Whether the sustain pedal is down, per input channel.
+    sustain_down: [bool; 16],
+    /// Whether the modifier CC is held, per input channel.
+    modifier_down: [bool; 16],
+    /// Last 7-bit mod wheel value, per input channel.
+    mod_wheel: [u8; 16],
+    /// Position in the spread cycle, per mapped program.
+    spread_cycles: [u8; 128],
+}
+
+const BANK_SELECT_MSB: u8 = 0;
+const BANK_SELECT_LSB: u8 = 32;
+const MOD_WHEEL: u8 = 1;
+const SUSTAIN_PEDAL: u8 = 64;
+const RESET_ALL_CONTROLLERS: u8 = 121;
+const ALL_NOTES_OFF: u8 = 123;
+/// Undefined CCs the debug dump reports on.
+const DEBUG_HELD_NOTES_CC: u8 = 102;
+const DEBUG_PROGRAM_CC: u8 = 103;
+
+/// Marks a CC in `last_cc_values` that hasn't been forwarded yet.
+const CC_UNSEEN: u8 = u8::MAX;
+
+/// Marks a note in `last_press` that hasn't been pressed yet.
+const NO_PRESS: u64 = u64::MAX;
+
+/// Stored in `learned_range` until a range has been learned.
+const NO_LEARNED_RANGE: u32 = u32::MAX;
+
+/// Stored in `last_program` until a Program Change has been sent.
+const NO_SAVED_PROGRAM: u32 = u32::MAX;
+
+/// What became of an incoming note-on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NoteOnResult {
+    /// The note was used by a control feature, like the setlist trigger.
+    Consumed,
+    /// The note converted, whether or not it produced a Program Change.
+    Converted,
+    /// The note fell outside the conversion range.
+    OutOfRange,
+}
+
+/// Upper bound on queued Program Changes. The queue never grows past its
+/// preallocated capacity so the audio thread doesn't allocate.
+const PENDING_CAPACITY: usize = 256;
+
+/// A Program Change scheduled for a later point on the sample clock, or the
+/// split note waiting behind one.
+#[derive(Debug, Clone, Copy)]
+struct PendingProgramChange {
+    pc: ProgramChange,
+    /// Absolute sample position at which the Program Change is sent.
+    due: u64,
+    source: PendingSource,
+}
+
+/// The held note whose program aftertouch steps from.
+#[derive(Debug, Clone, Copy)]
+struct PressureBase {
+    channel: u8,
+    note: u8,
+    /// The program the note selected itself.
+    pc: ProgramChange,
+    /// The pressure band last sent, 0 being the note's own program.
+    step: u8,
+}
+
+/// How Program Changes are put on the wire, snapshotted per send.
+#[derive(Debug, Clone, Copy)]
+struct EmitOptions {
+    /// Follow each program with Reset All Controllers.
+    reset_controllers: bool,
+    /// Send the program as this CC's value instead of a Program Change.
+    program_cc: Option<u8>,
+}
+
+/// The feature that queued a pending Program Change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PendingSource {
+    Coalesce,
+    Morph,
+    /// Waiting for the morph in progress to finish.
+    AfterMorph,
+    /// A held note re-announcing its program.
+    Resend,
+    /// Held back by the per-channel minimum interval.
+    Spacing,
+    /// Waiting for the grid line at `beats`. The due time is recomputed
+    /// every buffer in case the tempo changes.
+    Quantize { beats: f64 },
+    /// A split note held back by `pc_lead_samples`, sent in place of `pc`.
+    SplitNote(NoteEvent<OutputSysEx>),
 }
 
 #[derive(Params)]
@@ -24,7 +229,17 @@ struct MidiNoteToPcParams {
     #[id = "channel"]
     pub output_channel: IntParam,
 
-    /// Maximum note number to convert. Notes above this are ignored.
+    /// Which incoming channel Auto output follows.
+    #[id = "follow_mode"]
+    pub follow_mode: EnumParam<FollowMode>,
+
+    /// Minimum note number to convert. Notes below this are ignored; the
+    /// bound itself converts.
+    #[id = "min_note"]
+    pub min_note: IntParam,
+
+    /// Maximum note number to convert. Notes above this are ignored; the
+    /// bound itself converts.
     /// Default: 99 (as per spec), max: 127.
     #[id = "max_note"]
     pub max_note: IntParam,
@@ -32,6 +247,689 @@ struct MidiNoteToPcParams {
     /// Whether to pass through non-note MIDI events (CCs, pitch bend, etc.)
     #[id = "passthrough"]
     pub pass_through: BoolParam,
+
+    /// Pass notes outside the conversion range through as notes (with their
+    /// note-offs) instead of consuming them, e.g. to play a bass sound below
+    /// `min_note`.
+    #[id = "forward_out_of_range"]
+    pub forward_out_of_range_notes: BoolParam,
+
+    /// Drop passed-through CCs that repeat the previous value of the same
+    /// controller on the same channel.
+    #[id = "dedupe_cc"]
+    pub dedupe_cc: BoolParam,
+
+    /// Window in milliseconds during which rapid notes are merged: each new
+    /// note may replace the pending program, per `coalesce_pick`, and only
+    /// one is sent when the window closes. 0 = send immediately.
+    #[id = "coalesce_ms"]
+    pub coalesce_ms: FloatParam,
+
+    /// Which of the notes in a coalesce window selects the program.
+    #[id = "coalesce_pick"]
+    pub coalesce_pick: EnumParam<CoalescePick>,
+
+    /// What a note-off does to the program selected by its note-on.
+    #[id = "note_off_behavior"]
+    pub note_off_behavior: EnumParam<NoteOffBehavior>,
+
+    /// Program sent on note-off when `note_off_behavior` is `SendOffProgram`.
+    #[id = "off_program"]
+    pub off_program: IntParam,
+
+    /// Added to the note's own program on note-off when `note_off_behavior`
+    /// is `OffsetProgram`, e.g. to select a matching tail patch.
+    #[id = "off_program_offset"]
+    pub off_program_offset: IntParam,
+
+    /// Re-send the current program on the new channel whenever
+    /// `output_channel` changes, so the newly targeted synth is in sync.
+    #[id = "resend_on_channel_change"]
+    pub resend_on_channel_change: BoolParam,
+
+    /// Treat incoming note channels as 1–16 instead of 0–15, for hosts that
+    /// deliver them shifted by one.
+    #[id = "input_channel_one_based"]
+    pub input_channel_one_based: BoolParam,
+
+    /// Step through every program between the previous and the new one
+    /// instead of jumping, for gear that crossfades on each change.
+    #[id = "morph_steps"]
+    pub morph_steps: BoolParam,
+
+    /// Time the morph spreads its intermediate Program Changes over.
+    #[id = "morph_ms"]
+    pub morph_ms: FloatParam,
+
+    /// What a new program does to a morph still in progress.
+    #[id = "morph_interrupt"]
+    pub morph_interrupt: EnumParam<MorphInterrupt>,
+
+    /// Convert channel aftertouch into `pressure_cc` on the resolved output
+    /// channel instead of passing it through.
+    #[id = "pressure_to_cc"]
+    pub pressure_to_cc: BoolParam,
+
+    /// The CC number channel aftertouch is converted to.
+    #[id = "pressure_cc"]
+    pub pressure_cc: IntParam,
+
+    /// Program Changes are suppressed for this long after the plugin is
+    /// initialized, so nothing is sent amid project-load chaos.
+    #[id = "startup_grace_ms"]
+    pub startup_grace_ms: FloatParam,
+
+    /// Octaves added to every note before it is range-checked and mapped.
+    #[id = "octave_shift"]
+    pub octave_shift: IntParam,
+
+    /// Snap shifted notes to the nearest multiple of this many semitones
+    /// from the anchor before mapping, e.g. 2 for whole tones, so a wobbly
+    /// controller doesn't select the neighbouring program. 1 disables it.
+    #[id = "note_quantize_interval"]
+    pub note_quantize_interval: IntParam,
+
+    /// The note that selects program 0; every other note maps relative to
+    /// it. Notes below the anchor map to negative programs, which are
+    /// handled by `out_of_range_program`.
+    #[id = "anchor_note"]
+    pub anchor_note: IntParam,
+
+    /// Select bank and program with two separate notes: a note in the bank
+    /// zone picks the bank, the next note in the conversion range sends
+    /// that bank followed by its program.
+    #[id = "two_note_bank_program"]
+    pub two_note_bank_program: BoolParam,
+
+    /// Lowest note of the bank zone, selecting bank 0.
+    #[id = "bank_zone_low"]
+    pub bank_zone_low: IntParam,
+
+    /// Highest note of the bank zone.
+    #[id = "bank_zone_high"]
+    pub bank_zone_high: IntParam,
+
+    /// Hold Program Changes back until the next beat or bar while the
+    /// transport is running.
+    #[id = "quantize_to"]
+    pub quantize_to: EnumParam<QuantizeTo>,
+
+    /// The channel passed-through events are sent on, independent of the
+    /// Program Change channel. 0 keeps their original channel.
+    #[id = "passthrough_channel"]
+    pub passthrough_channel: IntParam,
+
+    /// Drop this many note-ons after the plugin is (re)activated, for
+    /// controllers that dump their state on connect.
+    #[id = "ignore_first_notes"]
+    pub ignore_first_notes: IntParam,
+
+    /// A CC that sends `trigger_cc_program` when it rises across
+    /// `trigger_cc_threshold`. -1 disables the trigger.
+    #[id = "trigger_cc"]
+    pub trigger_cc: IntParam,
+
+    #[id = "trigger_cc_threshold"]
+    pub trigger_cc_threshold: IntParam,
+
+    #[id = "trigger_cc_program"]
+    pub trigger_cc_program: IntParam,
+
+    /// Number of adjacent notes that select the same program, e.g. 12 for
+    /// one program per octave. Bands are counted from the anchor note.
+    #[id = "band_size"]
+    pub band_size: IntParam,
+
+    /// Maps each octave above the anchor to its own run of twelve
+    /// programs, starting this many programs above the previous octave's.
+    /// Replaces the bands; -1 disables it.
+    #[id = "program_offset_per_octave"]
+    pub program_offset_per_octave: IntParam,
+
+    /// Only white keys convert, spread evenly from program 0 on C-1 to 127
+    /// on G9, for controllers without black keys. Replaces the bands.
+    #[id = "white_keys_only"]
+    pub white_keys_only: BoolParam,
+
+    /// Echo every Program Change to this channel as well, e.g. for a
+    /// master controller that logs patch changes. 0 disables the echo.
+    #[id = "broadcast_channel"]
+    pub broadcast_channel: IntParam,
+
+    /// Also send a bare copy of every Program Change to this channel, with
+    /// `monitor_pc_offset` added to the program (wrapping), so a MIDI
+    /// monitor can tell the copies apart. 0 disables it.
+    #[id = "monitor_pc_channel"]
+    pub monitor_pc_channel: IntParam,
+
+    #[id = "monitor_pc_offset"]
+    pub monitor_pc_offset: IntParam,
+
+    /// Send each program at most once per channel within a single buffer,
+    /// when several notes in the same buffer map to it.
+    #[id = "collapse_buffer_duplicates"]
+    pub collapse_same_buffer_duplicates: BoolParam,
+
+    /// Pass CLAP polyphonic modulation events through. They carry a voice
+    /// ID but no MIDI channel, so `passthrough_channel` doesn't apply.
+    #[id = "forward_poly_modulation"]
+    pub forward_poly_modulation: BoolParam,
+
+    /// Pass CLAP monophonic automation events through. Like polyphonic
+    /// modulation they have no MIDI channel to remap.
+    #[id = "forward_mono_automation"]
+    pub forward_mono_automation: BoolParam,
+
+    /// Each note sends its program only the first time it is played, until
+    /// the plugin is reset.
+    #[id = "once_per_note"]
+    pub once_per_note: BoolParam,
+
+    /// Factor applied to the velocity of forwarded note-ons, e.g. for a
+    /// quieter pass-through layer. The result is clamped to 0–1.
+    #[id = "forward_velocity_scale"]
+    pub forward_velocity_scale: FloatParam,
+
+    /// Minimum velocity of forwarded note-ons, applied after the scale.
+    #[id = "forward_velocity_floor"]
+    pub forward_velocity_floor: FloatParam,
+
+    /// Send a Bank Select MSB before each Program Change, chosen by the
+    /// note's input channel from `channel_banks`.
+    #[id = "per_channel_bank"]
+    pub per_channel_bank: BoolParam,
+
+    /// Every in-range note selects this program instead of its mapped one.
+    /// -1 maps notes as usual.
+    #[id = "fixed_program"]
+    pub fixed_program: IntParam,
+
+    /// Send All Notes Off (CC 123) on the output channel when the last held
+    /// note is released.
+    #[id = "note_off_sends_all_notes_off"]
+    pub note_off_sends_all_notes_off: BoolParam,
+
+    /// Drop note-ons while this many notes are already held, so chords
+    /// only select one program. 0 disables the cap.
+    #[id = "max_held_notes"]
+    pub max_held_notes: IntParam,
+
+    /// The program follows the highest held note, re-sending whenever a
+    /// higher note arrives or the top note is released.
+    #[id = "track_top_note"]
+    pub track_top_note: BoolParam,
+
+    /// Note that re-enables conversion after the disarm note. -1 = none.
+    #[id = "arm_note"]
+    pub arm_note: IntParam,
+
+    /// Note that stops all conversion until the arm note. While disarmed,
+    /// notes pass through or are dropped per `pass_through`. -1 = none.
+    #[id = "disarm_note"]
+    pub disarm_note: IntParam,
+
+    /// Note that dumps the plugin's state as CCs instead of converting, for
+    /// debugging on a MIDI monitor: the held note count as CC 102 on the
+    /// output channel, then each channel's last program as CC 103 on that
+    /// channel. -1 = none.
+    #[id = "debug_dump_note"]
+    pub debug_dump_note: IntParam,
+
+    /// A CC that has to be at or above `modifier_threshold` on a note's
+    /// channel for the note to convert, like a shift key. Other notes play
+    /// normally. -1 disables the gate.
+    #[id = "modifier_cc"]
+    pub modifier_cc: IntParam,
+
+    #[id = "modifier_threshold"]
+    pub modifier_threshold: IntParam,
+
+    /// Converted notes also play on this channel, so one note both selects
+    /// a patch on the output channel and sounds on another. 0 = off.
+    #[id = "split_note_channel"]
+    pub split_note_channel: IntParam,
+
+    /// Samples the split note plays after its Program Change, for synths
+    /// that need a moment to switch patches.
+    #[id = "pc_lead_samples"]
+    pub pc_lead_samples: IntParam,
+
+    /// While the note that selected the current program is held, send the
+    /// program again at this interval, for devices that lose their state.
+    /// 0 disables it.
+    #[id = "resend_interval_ms"]
+    pub resend_interval_ms: FloatParam,
+
+    /// Program sent once all notes have been released for `idle_ms`, to put
+    /// the target back on a default patch during breaks. -1 disables it.
+    #[id = "idle_default_program"]
+    pub idle_default_program: IntParam,
+
+    /// How long there has to be no note activity before the idle default
+    /// program is sent.
+    #[id = "idle_ms"]
+    pub idle_ms: FloatParam,
+
+    /// What happens to computed programs outside 0–127.
+    #[id = "out_of_range_program"]
+    pub out_of_range_program: EnumParam<OutOfRangeProgram>,
+
+    /// How many patches the target synth has. Programs past the last one
+    /// are skipped, clamped or wrapped like out-of-range programs.
+    #[id = "device_patch_count"]
+    pub device_patch_count: IntParam,
+
+    /// A CC sent right after each converted Program Change, with a value
+    /// derived from the note, e.g. to set a macro on the new patch. -1 = off.
+    #[id = "note_cc"]
+    pub note_cc: IntParam,
+
+    /// Factor the note is multiplied by for the `note_cc` value.
+    #[id = "note_cc_scale"]
+    pub note_cc_scale: FloatParam,
+
+    /// Added to the scaled note for the `note_cc` value.
+    #[id = "note_cc_offset"]
+    pub note_cc_offset: IntParam,
+
+    /// Send each converted program together with the channel's mod wheel
+    /// value as one expression patch SysEx, in place of the Program Change.
+    #[id = "expression_patch_sysex"]
+    pub expression_patch_sysex: BoolParam,
+
+    /// Send the name of the note behind each converted program as a text
+    /// SysEx after it, for gear that can show it on a display.
+    #[id = "note_name_sysex"]
+    pub note_name_sysex: BoolParam,
+
+    /// Re-send the program saved with the project when it is loaded, so
+    /// external gear comes back in sync.
+    #[id = "recall_program_on_load"]
+    pub recall_program_on_load: BoolParam,
+
+    /// Stop sending Program Changes while everything that tracks the
+    /// current program keeps updating, so unfreezing picks up in a
+    /// consistent state.
+    #[id = "freeze"]
+    pub freeze: BoolParam,
+
+    /// Channel aftertouch steps the program of the held note upwards, one
+    /// program per band of pressure.
+    #[id = "pressure_selects_program"]
+    pub pressure_selects_program: BoolParam,
+
+    /// Number of pressure bands, and so of programs stepped through, in
+    /// `pressure_selects_program` mode.
+    #[id = "pressure_program_steps"]
+    pub pressure_program_steps: IntParam,
+
+    /// A note only sends its program when pressed twice within
+    /// `double_press_ms`, guarding against accidental patch changes.
+    #[id = "require_double_press"]
+    pub require_double_press: BoolParam,
+
+    #[id = "double_press_ms"]
+    pub double_press_ms: FloatParam,
+
+    /// Every converted note sends the next program in sequence, whatever its
+    /// pitch, wrapping back to 0 after `sequence_max`.
+    #[id = "sequential_programs"]
+    pub sequential_programs: BoolParam,
+
+    #[id = "sequence_max"]
+    pub sequence_max: IntParam,
+
+    /// Send at most one Program Change per beat while the transport is
+    /// playing, dropping the rest, to avoid mid-beat patch glitches.
+    #[id = "one_per_beat"]
+    pub one_per_beat: BoolParam,
+
+    /// Drop a Program Change that repeats its channel's last program within
+    /// this many beats while the transport is playing. 0 disables it.
+    #[id = "dedupe_beats"]
+    pub dedupe_beats: FloatParam,
+
+    /// Send Reset All Controllers (CC 121) right after each Program Change
+    /// to clear stale modulation on the new patch.
+    #[id = "reset_controllers_after_pc"]
+    pub reset_controllers_after_pc: BoolParam,
+
+    /// Minimum gap between Program Changes on the same channel, for slow
+    /// synths. Changes that come too soon are held back until the gap has
+    /// passed, with the latest one winning. 0 disables it.
+    #[id = "min_channel_interval_ms"]
+    pub min_channel_interval_ms: FloatParam,
+
+    /// Select patches with this CC instead of Program Change, for synths
+    /// that morph between patches by controller. The CC value is the
+    /// program the note maps to. -1 sends Program Changes.
+    #[id = "program_cc"]
+    pub program_cc: IntParam,
+
+    /// Which goes first when a note-off and a note-on arrive at the same
+    /// sample, as in fast legato playing.
+    #[id = "same_timing_order"]
+    pub same_timing_order: EnumParam<SameTimingOrder>,
+
+    /// Snap the final program down to a multiple of this, for devices that
+    /// only use every Nth program. 1 leaves programs as they are.
+    #[id = "program_step"]
+    pub program_step: IntParam,
+
+    /// Notes on this input channel are played through instead of being
+    /// converted, keeping one channel for playing normally. 0 = none.
+    #[id = "passthrough_channel_for_notes"]
+    pub passthrough_channel_for_notes: IntParam,
+
+    /// Notes on this input channel are commands instead of being
+    /// converted, by pitch class: C steps the last program down by one, D
+    /// steps it up, and E is a panic that drops every pending Program
+    /// Change and sends All Notes Off on all channels. With the `logging`
+    /// feature, F exports the Program Changes sent so far as a MIDI file.
+    /// Other notes are ignored. 0 = none.
+    #[id = "command_channel"]
+    pub command_channel: IntParam,
+
+    /// Re-send the last program of every channel saved with the project
+    /// when it is loaded, in channel order, to restore a whole rig. Takes
+    /// the place of `recall_program_on_load`.
+    #[id = "recall_all_channels_on_load"]
+    pub recall_all_channels_on_load: BoolParam,
+
+    /// Send the fixed program whenever the host restores the plugin's
+    /// state, so gear follows the host's presets for this plugin. Automating
+    /// the fixed program, or the host re-initializing the plugin, doesn't
+    /// send anything by itself. Goes out after any recalled programs.
+    #[id = "send_fixed_program_on_load"]
+    pub send_fixed_program_on_load: BoolParam,
+
+    /// While on, the lowest and highest notes played are recorded. When it
+    /// is switched off they replace the note range, spread over all 128
+    /// programs. Learning without playing clears the range.
+    #[id = "learn_range"]
+    pub learn_range: BoolParam,
+
+    /// Convert with the learned range. Off goes back to the note range
+    /// without forgetting the learned one.
+    #[id = "use_learned_range"]
+    pub use_learned_range: BoolParam,
+
+    /// Hold converted programs until the sustain pedal (CC 64) is
+    /// released, then send the latest one. Lets a chord be built up with the
+    /// pedal down and committed on release.
+    #[id = "commit_on_pedal_release"]
+    pub commit_on_pedal_release: BoolParam,
+
+    /// Pick the Bank Select LSB from the note's velocity, spread across
+    /// `velocity_bank_low`–`velocity_bank_high`, while the note still picks
+    /// the program.
+    #[id = "velocity_bank_lsb"]
+    pub velocity_bank_lsb: BoolParam,
+
+    #[id = "velocity_bank_low"]
+    pub velocity_bank_low: IntParam,
+
+    #[id = "velocity_bank_high"]
+    pub velocity_bank_high: IntParam,
+
+    /// Pick the Bank Select MSB from the note's velocity, spread across
+    /// `velocity_msb_low`–`velocity_msb_high`, for libraries indexed by
+    /// MSB. `velocity_msb_lsb` goes with it unless velocity also picks the
+    /// LSB. A two-note bank still wins, a per-channel bank doesn't.
+    #[id = "velocity_bank_msb"]
+    pub velocity_bank_msb: BoolParam,
+
+    #[id = "velocity_msb_low"]
+    pub velocity_msb_low: IntParam,
+
+    #[id = "velocity_msb_high"]
+    pub velocity_msb_high: IntParam,
+
+    /// -1 = no LSB.
+    #[id = "velocity_msb_lsb"]
+    pub velocity_msb_lsb: IntParam,
+
+    /// Expand incoming Program Changes into a Bank Select from
+    /// `expand_bank_msb`/`expand_bank_lsb` plus the Program Change, so
+    /// gear behind a simple controller lands in the right bank.
+    #[id = "expand_program_changes"]
+    pub expand_program_changes: BoolParam,
+
+    /// -1 sends no MSB.
+    #[id = "expand_bank_msb"]
+    pub expand_bank_msb: IntParam,
+
+    /// -1 sends no LSB.
+    #[id = "expand_bank_lsb"]
+    pub expand_bank_lsb: IntParam,
+
+    /// Repeated triggers of the same program cycle through the programs up
+    /// to this far either side of it, for variation: p, p+1, p-1, p+2, ...
+    /// 0 disables it.
+    #[id = "spread"]
+    pub spread: IntParam,
+
+    /// Number of setlist entries in use. 0 disables the setlist.
+    #[id = "setlist_length"]
+    pub setlist_length: IntParam,
+
+    /// Pressing this note sends the next setlist program instead of
+    /// converting the note.
+    #[id = "setlist_trigger"]
+    pub setlist_trigger_note: IntParam,
+
+    #[nested(array, group = "Setlist")]
+    pub setlist: [SetlistEntryParams; SETLIST_CAPACITY],
+
+    /// The bank each input channel selects when `per_channel_bank` is on.
+    #[nested(array, group = "Channel Banks")]
+    pub channel_banks: [ChannelBankParams; 16],
+
+    /// Output channels that never receive Program Changes. Notes for a
+    /// muted channel are still consumed.
+    #[nested(array, group = "Muted Channels")]
+    pub muted_channels: [MutedChannelParams; 16],
+
+    /// The setlist entry the next trigger press sends.
+    #[persist = "setlist_index"]
+    pub setlist_index: AtomicU32,
+
+    /// The program the next note sends in `sequential_programs` mode.
+    #[persist = "sequence_index"]
+    pub sequence_index: AtomicU32,
+
+    /// Cleared by the disarm note, set again by the arm note.
+    #[persist = "armed"]
+    pub armed: AtomicBool,
+
+    /// Remaps computed programs: program `n` is sent as entry `n`. Programs
+    /// past the end of the map are sent unchanged.
+    #[persist = "program_map"]
+    pub program_map: RwLock<Vec<u8>>,
+
+    /// Per-note overrides as (note, channel, program): the note selects that
+    /// program instead of its mapped one, and is sent on the 1-based channel
+    /// unless it is 0. E.g. one drum synth per pad on channel 10.
+    #[persist = "note_map"]
+    pub note_map: RwLock<Vec<(u8, u8, u8)>>,
+
+    /// The last Program Change sent, `NO_SAVED_PROGRAM` in a fresh instance.
+    #[persist = "last_program"]
+    pub last_program: AtomicU32,
+    #[persist = "last_channel"]
+    pub last_channel: AtomicU32,
+
+    /// The range recorded by `learn_range` as `min << 8 | max`, or
+    /// `NO_LEARNED_RANGE`.
+    #[persist = "learned_range"]
+    pub learned_range: AtomicU32,
+
+    /// The last program sent on each channel, `None` where nothing was sent.
+    #[persist = "channel_programs"]
+    pub channel_programs: RwLock<[Option<u8>; 16]>,
+
+    /// Raised when the host restores saved state, for the recall on load.
+    #[persist = "state_loaded"]
+    pub state_loaded: StateLoaded,
+}
+
+/// A persisted field without data of its own. Restoring state sets every
+/// persisted field that was saved, so this one records that a load
+/// happened, which a plain re-initialization can't tell apart.
+#[derive(Default)]
+struct StateLoaded(AtomicBool);
+
+impl StateLoaded {
+    /// Whether state was restored since the last call.
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl PersistentField<'_, bool> for StateLoaded {
+    fn set(&self, _new_value: bool) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn map<F, R>(&self, f: F) -> R
+    where
+        F: Fn(&bool) -> R,
+    {
+        f(&true)
+    }
+}
+
+/// Maximum number of programs in the setlist.
+const SETLIST_CAPACITY: usize = 16;
+
+#[derive(Params)]
+struct SetlistEntryParams {
+    #[id = "program"]
+    pub program: IntParam,
+}
+
+#[derive(Params)]
+struct ChannelBankParams {
+    #[id = "bank"]
+    pub bank: IntParam,
+}
+
+#[derive(Params)]
+struct MutedChannelParams {
+    #[id = "muted"]
+    pub muted: BoolParam,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum QuantizeTo {
+    #[name = "Off"]
+    Off,
+    #[name = "Beat"]
+    Beat,
+    #[name = "Bar"]
+    Bar,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum CoalescePick {
+    /// Each note replaces the previous one.
+    #[name = "Latest"]
+    Latest,
+    /// The note with the highest velocity, the lowest one on a tie, as
+    /// for a chord.
+    #[name = "Loudest"]
+    Loudest,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum NoteOffBehavior {
+    /// Keep the program selected by the note-on (Program Change has no "off").
+    #[name = "Leave"]
+    Leave,
+    /// Go back to program 0, the power-on default on most devices.
+    #[name = "Revert to Default"]
+    RevertDefault,
+    /// Send the configured `off_program`.
+    #[name = "Send Off Program"]
+    SendOffProgram,
+    /// Send the note's own program shifted by `off_program_offset`.
+    #[name = "Offset Program"]
+    OffsetProgram,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum MorphInterrupt {
+    /// Abandon the morph and morph to the new program from wherever the
+    /// old one got to.
+    #[name = "Restart"]
+    Restart,
+    /// Abandon the morph and send the new program right away.
+    #[name = "Jump"]
+    Jump,
+    /// Let the morph finish, then send the new program without morphing.
+    /// Only the latest new program is kept.
+    #[name = "Finish Then Jump"]
+    FinishThenJump,
+    /// Let the morph finish, then morph on to the new program.
+    #[name = "Queue"]
+    Queue,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum FollowMode {
+    /// Each note's program goes out on that note's channel.
+    #[name = "Per Note"]
+    PerNote,
+    /// Every program in a phrase goes out on the channel of the note that
+    /// started it, until all notes are released.
+    #[name = "Per Phrase"]
+    PerPhrase,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SameTimingOrder {
+    /// Keep the host's order.
+    #[name = "As Received"]
+    AsReceived,
+    /// Release first, so the new note sees the old one as gone.
+    #[name = "Note-Offs First"]
+    NoteOffsFirst,
+    /// Press first, so the new note sees the old one as still held.
+    #[name = "Note-Ons First"]
+    NoteOnsFirst,
+}
+
+/// Shows the -1 of an optional value as "Off".
+fn v2s_off_or_value() -> Arc<dyn Fn(i32) -> String + Send + Sync> {
+    Arc::new(|value| {
+        if value < 0 {
+            "Off".to_string()
+        } else {
+            value.to_string()
+        }
+    })
+}
+
+/// Like `v2s_off_or_value`, for CC numbers.
+fn v2s_off_or_cc() -> Arc<dyn Fn(i32) -> String + Send + Sync> {
+    Arc::new(|value| {
+        if value < 0 {
+            "Off".to_string()
+        } else {
+            format!("CC {}", value)
+        }
+    })
+}
+
+/// Shows the 0 of an optional 1–16 channel as "Off".
+fn v2s_off_or_channel() -> Arc<dyn Fn(i32) -> String + Send + Sync> {
+    Arc::new(|value| {
+        if value == 0 {
+            "Off".to_string()
+        } else {
+            format!("Ch {}", value)
+        }
+    })
 }
 
 impl Default for MidiNoteToPcParams {
@@ -51,6 +949,14 @@ impl Default for MidiNoteToPcParams {
                 }
             })),
 
+            follow_mode: EnumParam::new("Follow Mode", FollowMode::PerNote),
+
+            min_note: IntParam::new(
+                "Min Note",
+                0,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
             max_note: IntParam::new(
                 "Max Note",
                 99,
@@ -58,92 +964,2316 @@ impl Default for MidiNoteToPcParams {
             ),
 
             pass_through: BoolParam::new("Pass Through Other MIDI", true),
-        }
-    }
-}
 
-impl Default for MidiNoteToPc {
-    fn default() -> Self {
-        Self {
-            params: Arc::new(MidiNoteToPcParams::default()),
-        }
-    }
-}
+            forward_out_of_range_notes: BoolParam::new("Forward Out-of-Range Notes", false),
 
-impl Plugin for MidiNoteToPc {
-    const NAME: &'static str = "MIDI Note to Program Change";
-    const VENDOR: &'static str = "Nico";
-    const URL: &'static str = "";
-    const EMAIL: &'static str = "";
-    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+            dedupe_cc: BoolParam::new("Drop Repeated CCs", false),
 
-    // No audio I/O — this is a pure MIDI effect
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[];
+            coalesce_ms: FloatParam::new(
+                "Coalesce Window",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1000.0 },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
 
-    // Accept and output MIDI (including CCs, pitch bend, etc.)
-    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
-    const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
-    const SAMPLE_ACCURATE_AUTOMATION: bool = true;
+            coalesce_pick: EnumParam::new("Coalesce Pick", CoalescePick::Latest),
 
-    type SysExMessage = ();
-    type BackgroundTask = ();
+            note_off_behavior: EnumParam::new("Note Off", NoteOffBehavior::Leave),
 
-    fn params(&self) -> Arc<dyn Params> {
-        self.params.clone()
-    }
+            off_program: IntParam::new(
+                "Off Program",
+                0,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
 
-    fn process(
-        &mut self,
-        _buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
-        context: &mut impl ProcessContext<Self>,
-    ) -> ProcessStatus {
-        let output_ch = self.params.output_channel.value() as u8;
-        let max_note = self.params.max_note.value() as u8;
-        let pass_through = self.params.pass_through.value();
+            off_program_offset: IntParam::new(
+                "Off Program Offset",
+                1,
+                IntRange::Linear {
+                    min: -127,
+                    max: 127,
+                },
+            ),
 
-        while let Some(event) = context.next_event() {
-            match event {
-                // ── Note On → Program Change ──────────────────────────
-                NoteEvent::NoteOn {
-                    timing,
-                    channel,
-                    note,
-                    ..
-                } => {
-                    // Only convert notes within the configured range
-                    if note <= max_note {
-                        let ch = if output_ch == 0 {
-                            channel // follow the incoming channel
-                        } else {
-                            output_ch - 1 // user picks 1–16, nih-plug uses 0–15
-                        };
+            resend_on_channel_change: BoolParam::new("Resend on Channel Change", false),
 
-                        context.send_event(NoteEvent::MidiProgramChange {
-                            timing,
-                            channel: ch,
-                            program: note,
-                        });
-                    }
-                    // Note is consumed — not forwarded
-                }
+            input_channel_one_based: BoolParam::new("1-Based Input Channels", false),
 
-                // ── Note Off → silently consumed ──────────────────────
-                NoteEvent::NoteOff { .. } => {
-                    // Program Change has no "off" concept — just drop it
-                }
+            morph_steps: BoolParam::new("Program Morph", false),
 
-                // ── Everything else → pass through (if enabled) ───────
-                other => {
-                    if pass_through {
-                        context.send_event(other);
-                    }
-                }
-            }
-        }
+            morph_ms: FloatParam::new(
+                "Morph Time",
+                100.0,
+                FloatRange::Linear { min: 0.0, max: 2000.0 },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
 
-        ProcessStatus::Normal
-    }
+            morph_interrupt: EnumParam::new("Morph Interrupt", MorphInterrupt::Restart),
+
+            pressure_to_cc: BoolParam::new("Aftertouch to CC", false),
+
+            pressure_cc: IntParam::new(
+                "Aftertouch CC",
+                1, // mod wheel
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            startup_grace_ms: FloatParam::new(
+                "Startup Grace",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 10000.0 },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+
+            octave_shift: IntParam::new(
+                "Octave Shift",
+                0,
+                IntRange::Linear { min: -10, max: 10 },
+            )
+            .with_unit(" oct"),
+
+            note_quantize_interval: IntParam::new(
+                "Note Quantize Interval",
+                1,
+                IntRange::Linear { min: 1, max: 12 },
+            )
+            .with_unit(" st"),
+
+            anchor_note: IntParam::new(
+                "Anchor Note",
+                0,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            two_note_bank_program: BoolParam::new("Two-Note Bank/Program", false),
+
+            bank_zone_low: IntParam::new(
+                "Bank Zone Low",
+                100,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            bank_zone_high: IntParam::new(
+                "Bank Zone High",
+                127,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            quantize_to: EnumParam::new("Quantize", QuantizeTo::Off),
+
+            passthrough_channel: IntParam::new(
+                "Pass Through Channel",
+                0, // 0 = keep the original channel
+                IntRange::Linear { min: 0, max: 16 },
+            )
+            .with_value_to_string(Arc::new(|value| {
+                if value == 0 {
+                    "Original".to_string()
+                } else {
+                    format!("Ch {}", value)
+                }
+            })),
+
+            ignore_first_notes: IntParam::new(
+                "Ignore First Notes",
+                0,
+                IntRange::Linear { min: 0, max: 32 },
+            ),
+
+            trigger_cc: IntParam::new(
+                "Trigger CC",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_cc()),
+
+            trigger_cc_threshold: IntParam::new(
+                "Trigger CC Threshold",
+                64,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            trigger_cc_program: IntParam::new(
+                "Trigger CC Program",
+                0,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            band_size: IntParam::new(
+                "Band Size",
+                1,
+                IntRange::Linear { min: 1, max: 128 },
+            ),
+
+            program_offset_per_octave: IntParam::new(
+                "Program Offset Per Octave",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_value()),
+
+            white_keys_only: BoolParam::new("White Keys Only", false),
+
+            broadcast_channel: IntParam::new(
+                "Broadcast Channel",
+                0, // 0 = off
+                IntRange::Linear { min: 0, max: 16 },
+            )
+            .with_value_to_string(v2s_off_or_channel()),
+
+            monitor_pc_channel: IntParam::new(
+                "Monitor PC Channel",
+                0, // 0 = off
+                IntRange::Linear { min: 0, max: 16 },
+            )
+            .with_value_to_string(v2s_off_or_channel()),
+
+            monitor_pc_offset: IntParam::new(
+                "Monitor PC Offset",
+                0,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            collapse_same_buffer_duplicates: BoolParam::new("Collapse Duplicates", false),
+
+            forward_poly_modulation: BoolParam::new("Pass Through Poly Modulation", true),
+
+            forward_mono_automation: BoolParam::new("Pass Through Mono Automation", true),
+
+            once_per_note: BoolParam::new("Once per Note", false),
+
+            forward_velocity_scale: FloatParam::new(
+                "Forward Velocity Scale",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 2.0 },
+            )
+            .with_unit("x")
+            .with_step_size(0.01),
+
+            forward_velocity_floor: FloatParam::new(
+                "Forward Velocity Floor",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_step_size(0.01),
+
+            per_channel_bank: BoolParam::new("Per-Channel Bank", false),
+
+            fixed_program: IntParam::new(
+                "Fixed Program",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_value()),
+
+            note_off_sends_all_notes_off: BoolParam::new("All Notes Off On Release", false),
+
+            max_held_notes: IntParam::new(
+                "Max Held Notes",
+                0,
+                IntRange::Linear { min: 0, max: 16 },
+            )
+            .with_value_to_string(Arc::new(|value| {
+                if value == 0 {
+                    "Off".to_string()
+                } else {
+                    value.to_string()
+                }
+            })),
+
+            track_top_note: BoolParam::new("Track Top Note", false),
+
+            arm_note: IntParam::new(
+                "Arm Note",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_value()),
+
+            disarm_note: IntParam::new(
+                "Disarm Note",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_value()),
+
+            debug_dump_note: IntParam::new(
+                "Debug Dump Note",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_value()),
+
+            modifier_cc: IntParam::new(
+                "Modifier CC",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_cc()),
+
+            modifier_threshold: IntParam::new(
+                "Modifier Threshold",
+                64,
+                IntRange::Linear { min: 1, max: 127 },
+            ),
+
+            split_note_channel: IntParam::new(
+                "Split Note Channel",
+                0,
+                IntRange::Linear { min: 0, max: 16 },
+            )
+            .with_value_to_string(v2s_off_or_channel()),
+
+            pc_lead_samples: IntParam::new("PC Lead", 0, IntRange::Linear { min: 0, max: 4800 })
+                .with_unit(" samples"),
+
+            resend_interval_ms: FloatParam::new(
+                "Resend Interval",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 10000.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(10.0),
+
+            idle_default_program: IntParam::new(
+                "Idle Default Program",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_value()),
+
+            idle_ms: FloatParam::new(
+                "Idle Time",
+                10000.0,
+                FloatRange::Linear {
+                    min: 100.0,
+                    max: 600000.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(100.0),
+
+            out_of_range_program: EnumParam::new("Out of Range Program", OutOfRangeProgram::Skip),
+
+            device_patch_count: IntParam::new(
+                "Device Patch Count",
+                128,
+                IntRange::Linear { min: 1, max: 128 },
+            ),
+
+            note_cc: IntParam::new(
+                "Note CC",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_cc()),
+
+            note_cc_scale: FloatParam::new(
+                "Note CC Scale",
+                1.0,
+                FloatRange::Linear {
+                    min: -4.0,
+                    max: 4.0,
+                },
+            )
+            .with_unit("x")
+            .with_step_size(0.01),
+
+            note_cc_offset: IntParam::new(
+                "Note CC Offset",
+                0,
+                IntRange::Linear {
+                    min: -127,
+                    max: 127,
+                },
+            ),
+
+            expression_patch_sysex: BoolParam::new("Expression Patch SysEx", false),
+
+            note_name_sysex: BoolParam::new("Note Name SysEx", false),
+
+            recall_program_on_load: BoolParam::new("Recall Program on Load", false),
+
+            freeze: BoolParam::new("Freeze", false),
+
+            pressure_selects_program: BoolParam::new("Pressure Selects Program", false),
+
+            pressure_program_steps: IntParam::new(
+                "Pressure Program Steps",
+                4,
+                IntRange::Linear { min: 2, max: 16 },
+            ),
+
+            require_double_press: BoolParam::new("Require Double Press", false),
+
+            double_press_ms: FloatParam::new(
+                "Double Press Window",
+                400.0,
+                FloatRange::Linear {
+                    min: 50.0,
+                    max: 2000.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(10.0),
+
+            sequential_programs: BoolParam::new("Sequential Programs", false),
+
+            sequence_max: IntParam::new(
+                "Sequence Max",
+                127,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            one_per_beat: BoolParam::new("One per Beat", false),
+
+            dedupe_beats: FloatParam::new(
+                "Dedupe Window",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 4.0 },
+            )
+            .with_unit(" beats")
+            .with_step_size(0.125),
+
+            reset_controllers_after_pc: BoolParam::new("Reset Controllers After PC", false),
+
+            min_channel_interval_ms: FloatParam::new(
+                "Min Channel Interval",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 2000.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+
+            program_cc: IntParam::new(
+                "Program CC",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_cc()),
+
+            same_timing_order: EnumParam::new("Same Timing Order", SameTimingOrder::AsReceived),
+
+            program_step: IntParam::new(
+                "Program Step",
+                1,
+                IntRange::Linear { min: 1, max: 64 },
+            ),
+
+            passthrough_channel_for_notes: IntParam::new(
+                "Pass Through Notes Channel",
+                0,
+                IntRange::Linear { min: 0, max: 16 },
+            )
+            .with_value_to_string(v2s_off_or_channel()),
+
+            command_channel: IntParam::new(
+                "Command Channel",
+                0,
+                IntRange::Linear { min: 0, max: 16 },
+            )
+            .with_value_to_string(v2s_off_or_channel()),
+
+            recall_all_channels_on_load: BoolParam::new("Recall All Channels on Load", false),
+
+            send_fixed_program_on_load: BoolParam::new("Send Fixed Program on Load", false),
+
+            learn_range: BoolParam::new("Learn Range", false),
+
+            use_learned_range: BoolParam::new("Use Learned Range", true),
+
+            commit_on_pedal_release: BoolParam::new("Commit on Pedal Release", false),
+
+            velocity_bank_lsb: BoolParam::new("Velocity Bank LSB", false),
+
+            velocity_bank_low: IntParam::new(
+                "Velocity Bank Low",
+                0,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            velocity_bank_high: IntParam::new(
+                "Velocity Bank High",
+                3,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            velocity_bank_msb: BoolParam::new("Velocity Bank MSB", false),
+
+            velocity_msb_low: IntParam::new(
+                "Velocity MSB Low",
+                0,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            velocity_msb_high: IntParam::new(
+                "Velocity MSB High",
+                3,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            velocity_msb_lsb: IntParam::new(
+                "Velocity MSB LSB",
+                -1, // -1 = off
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_value()),
+
+            expand_program_changes: BoolParam::new("Expand Program Changes", false),
+
+            expand_bank_msb: IntParam::new(
+                "Expand Bank MSB",
+                -1, // -1 = none
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_value()),
+
+            expand_bank_lsb: IntParam::new(
+                "Expand Bank LSB",
+                -1, // -1 = none
+                IntRange::Linear { min: -1, max: 127 },
+            )
+            .with_value_to_string(v2s_off_or_value()),
+
+            spread: IntParam::new(
+                "Spread",
+                0,
+                IntRange::Linear { min: 0, max: 8 },
+            ),
+
+            setlist_length: IntParam::new(
+                "Setlist Length",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: SETLIST_CAPACITY as i32,
+                },
+            ),
+
+            setlist_trigger_note: IntParam::new(
+                "Setlist Trigger Note",
+                127,
+                IntRange::Linear { min: 0, max: 127 },
+            ),
+
+            setlist: std::array::from_fn(|index| SetlistEntryParams {
+                program: IntParam::new(
+                    format!("Setlist {} Program", index + 1),
+                    index as i32,
+                    IntRange::Linear { min: 0, max: 127 },
+                ),
+            }),
+
+            channel_banks: std::array::from_fn(|channel| ChannelBankParams {
+                bank: IntParam::new(
+                    format!("Ch {} Bank", channel + 1),
+                    0,
+                    IntRange::Linear { min: 0, max: 127 },
+                ),
+            }),
+
+            muted_channels: std::array::from_fn(|channel| MutedChannelParams {
+                muted: BoolParam::new(format!("Ch {} Muted", channel + 1), false),
+            }),
+
+            setlist_index: AtomicU32::new(0),
+            sequence_index: AtomicU32::new(0),
+            armed: AtomicBool::new(true),
+            program_map: RwLock::new(Vec::new()),
+            note_map: RwLock::new(Vec::new()),
+            last_program: AtomicU32::new(NO_SAVED_PROGRAM),
+            last_channel: AtomicU32::new(0),
+            learned_range: AtomicU32::new(NO_LEARNED_RANGE),
+            channel_programs: RwLock::new([None; 16]),
+            state_loaded: StateLoaded::default(),
+        }
+    }
+}
+
+impl Default for MidiNoteToPc {
+    fn default() -> Self {
+        Self {
+            params: Arc::new(MidiNoteToPcParams::default()),
+
+            sample_rate: 44100.0,
+            sample_clock: 0,
+            samples_since_init: 0,
+            pending: Vec::with_capacity(PENDING_CAPACITY),
+            grid: None,
+
+            last_pc: None,
+            last_output_channel: None,
+            forwarded_notes: NoteSet::default(),
+            selecting_notes: NoteSet::default(),
+            pending_bank: None,
+            held_notes: 0,
+            last_note_at: None,
+            phrase_channel: 0,
+            fired_notes: 0,
+            sent_this_buffer: NoteSet::default(),
+            trigger_cc_values: [0; 16],
+            notes_to_ignore: 0,
+            ignored_notes: NoteSet::default(),
+            last_cc_values: [[CC_UNSEEN; 128]; 16],
+            top_notes: NoteSet::default(),
+            resend_note: None,
+            pressure_base: None,
+            last_press: [NO_PRESS; 128],
+            last_pc_beat: None,
+            last_pc_beats: [None; 16],
+            last_sent_at: [None; 16],
+            split_channels: [[0; 128]; 16],
+            program_map: std::array::from_fn(|program| program as u8),
+            note_map: [None; 128],
+            recall_pending: false,
+            channel_programs: [None; 16],
+            channel_programs_dirty: false,
+            learning: false,
+            pedal_pc: None,
+            coalesced_note: None,
+            sustain_down: [false; 16],
+            modifier_down: [false; 16],
+            mod_wheel: [0; 16],
+            spread_cycles: [0; 128],
+        }
+    }
+}
+
+impl Plugin for MidiNoteToPc {
+    const NAME: &'static str = "MIDI Note to Program Change";
+    const VENDOR: &'static str = "Nico";
+    const URL: &'static str = "";
+    const EMAIL: &'static str = "";
+    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+    // No audio I/O — this is a pure MIDI effect
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[];
+
+    // Accept and output MIDI (including CCs, pitch bend, etc.)
+    // nih-plug exposes a single note port in each direction, so Program
+    // Changes and passed-through events always share the main output port.
+    // Use `output_channel` to keep them apart downstream.
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
+    const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
+    const SAMPLE_ACCURATE_AUTOMATION: bool = true;
+
+    type SysExMessage = OutputSysEx;
+    #[cfg(feature = "logging")]
+    type BackgroundTask = program_log::Task;
+    #[cfg(not(feature = "logging"))]
+    type BackgroundTask = ();
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    #[cfg(feature = "logging")]
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        Box::new(program_log::executor())
+    }
+
+    fn initialize(
+        &mut self,
+        _audio_io_layout: &AudioIOLayout,
+        buffer_config: &BufferConfig,
+        _context: &mut impl InitContext<Self>,
+    ) -> bool {
+        self.sample_rate = buffer_config.sample_rate;
+        self.samples_since_init = 0;
+        self.notes_to_ignore = self.params.ignore_first_notes.value() as u32;
+        // The wrapper restores saved state before initializing, a host
+        // changing the sample rate re-initializes without one
+        self.recall_pending = self.params.state_loaded.take();
+        if let Ok(saved) = self.params.channel_programs.read() {
+            self.channel_programs = *saved;
+        }
+        true
+    }
+
+    fn reset(&mut self) {
+        self.sample_clock = 0;
+        self.pending.clear();
+        self.last_pc = None;
+        self.last_output_channel = None;
+        self.forwarded_notes.clear();
+        self.selecting_notes.clear();
+        self.pending_bank = None;
+        self.held_notes = 0;
+        self.last_note_at = None;
+        self.last_cc_values = [[CC_UNSEEN; 128]; 16];
+        self.fired_notes = 0;
+        self.trigger_cc_values = [0; 16];
+        self.notes_to_ignore = self.params.ignore_first_notes.value() as u32;
+        self.ignored_notes.clear();
+        self.top_notes.clear();
+        self.resend_note = None;
+        self.pressure_base = None;
+        self.last_press = [NO_PRESS; 128];
+        self.last_pc_beat = None;
+        self.last_pc_beats = [None; 16];
+        self.last_sent_at = [None; 16];
+        self.pedal_pc = None;
+        self.coalesced_note = None;
+        self.sustain_down = [false; 16];
+        self.modifier_down = [false; 16];
+        self.mod_wheel = [0; 16];
+        self.spread_cycles = [0; 128];
+        self.split_channels = [[0; 128]; 16];
+    }
+
+    fn process(
+        &mut self,
+        buffer: &mut Buffer,
+        _aux: &mut AuxiliaryBuffers,
+        context: &mut impl ProcessContext<Self>,
+    ) -> ProcessStatus {
+        let grid = TransportGrid::new(context.transport());
+        self.process_events(context, buffer.samples(), grid);
+
+        ProcessStatus::Normal
+    }
+}
+
+impl MidiNoteToPc {
+    /// Processes one buffer of `samples` samples. This is all of
+    /// `process()` but reading the host's transport, so it can also be
+    /// driven without a host.
+    fn process_events(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        samples: usize,
+        grid: Option<TransportGrid>,
+    ) {
+        self.sent_this_buffer.clear();
+        self.refresh_program_map();
+        self.refresh_note_map();
+
+        // Each learning pass starts from scratch
+        let learning = self.params.learn_range.value();
+        if learning && !self.learning {
+            self.params
+                .learned_range
+                .store(NO_LEARNED_RANGE, Ordering::Relaxed);
+        }
+        self.learning = learning;
+
+        let use_learned_range = self.params.use_learned_range.value() && !learning;
+        let settings = ConvertSettings {
+            output_channel: self.params.output_channel.value() as u8,
+            min_note: self.params.min_note.value() as u8,
+            max_note: self.params.max_note.value() as u8,
+            learned_range: self.learned_range().filter(|_| use_learned_range),
+            octave_shift: self.params.octave_shift.value() as i8,
+            note_quantize_interval: self.params.note_quantize_interval.value() as u8,
+            anchor_note: self.params.anchor_note.value() as u8,
+            band_size: self.params.band_size.value() as u8,
+            program_offset_per_octave: u8::try_from(self.params.program_offset_per_octave.value())
+                .ok(),
+            white_keys_only: self.params.white_keys_only.value(),
+            fixed_program: u8::try_from(self.params.fixed_program.value()).ok(),
+            program_map: self.program_map,
+            note_map: self.note_map,
+            program_step: self.params.program_step.value() as u8,
+            out_of_range_program: self.params.out_of_range_program.value(),
+            patch_count: self.params.device_patch_count.value() as u8,
+        };
+        let pass_through = self.params.pass_through.value();
+        let one_based = self.params.input_channel_one_based.value();
+        let forward_out_of_range = self.params.forward_out_of_range_notes.value();
+        let dedupe_cc = self.params.dedupe_cc.value();
+        let max_held_notes = self.params.max_held_notes.value() as u32;
+        let split_note_channel = self.params.split_note_channel.value() as u8;
+        let notes_passthrough_channel = self.params.passthrough_channel_for_notes.value() as u8;
+        let all_notes_off_on_release = self.params.note_off_sends_all_notes_off.value();
+        let passthrough_channel = self.params.passthrough_channel.value() as u8;
+        let pressure_to_cc = self.params.pressure_to_cc.value();
+        let pressure_cc = self.params.pressure_cc.value() as u8;
+        let note_off_behavior = self.params.note_off_behavior.value();
+        let off_program = self.params.off_program.value() as u8;
+        let off_program_offset = self.params.off_program_offset.value() as i16;
+
+        if std::mem::take(&mut self.recall_pending) {
+            self.recall_on_load(context, &settings);
+        }
+
+        // With sample-accurate automation the buffer is split at parameter
+        // changes, so a changed channel takes effect at sample 0 here
+        let channel_changed = self
+            .last_output_channel
+            .is_some_and(|last| last != settings.output_channel);
+        self.last_output_channel = Some(settings.output_channel);
+        if channel_changed && self.params.resend_on_channel_change.value() {
+            if let Some(last) = self.last_pc {
+                // In Auto mode there is no incoming note to follow, so the
+                // program stays on the channel it was last sent to
+                let pc = ProgramChange {
+                    channel: resolve_channel(settings.output_channel, last.channel),
+                    ..last
+                };
+                self.send_program_change(context, 0, pc);
+            }
+        }
+
+        let buffer_start = self.sample_clock;
+        let buffer_end = buffer_start + samples as u64;
+        self.grid = grid;
+        self.retime_quantized(buffer_start);
+
+        let order = self.params.same_timing_order.value();
+        let mut events = OrderedEvents::default();
+        let last_sample = (samples as u32).saturating_sub(1);
+        while let Some(event) = next_ordered_event(context, &mut events, order) {
+            // A malformed host could send events past the end of the buffer,
+            // which would then go out with an invalid timing
+            nih_debug_assert!(event.timing() <= last_sample);
+            let event = clamp_timing(event, last_sample);
+            let now = buffer_start + event.timing() as u64;
+            self.flush_pending(now, buffer_start, context);
+
+            // The trigger CC still goes through the normal CC handling below
+            if let NoteEvent::MidiCC {
+                timing,
+                channel,
+                cc,
+                value,
+            } = event
+            {
+                if cc as i32 == self.params.trigger_cc.value() {
+                    let channel = normalize_input_channel(channel, one_based);
+                    self.trigger_cc(context, &settings, timing, channel, value);
+                }
+            }
+            // So does the sustain pedal, which may commit a held-back program
+            if let NoteEvent::MidiCC {
+                timing,
+                channel,
+                cc: SUSTAIN_PEDAL,
+                value,
+            } = event
+            {
+                let channel = normalize_input_channel(channel, one_based);
+                self.sustain_pedal(context, timing, channel, value);
+            }
+            // Likewise for aftertouch stepping the held note's program
+            if let NoteEvent::MidiChannelPressure {
+                timing,
+                channel,
+                pressure,
+            } = event
+            {
+                let channel = normalize_input_channel(channel, one_based);
+                self.pressure_program(context, &settings, timing, channel, pressure);
+            }
+            // As is the modifier CC gating conversion
+            if let NoteEvent::MidiCC {
+                channel, cc, value, ..
+            } = event
+            {
+                if cc as i32 == self.params.modifier_cc.value() {
+                    let channel = normalize_input_channel(channel, one_based);
+                    let threshold = self.params.modifier_threshold.value() as u8;
+                    self.modifier_down[(channel & 0x0f) as usize] =
+                        (value * 127.0).round() as u8 >= threshold;
+                }
+            }
+            // The mod wheel is only tracked, for the expression patch SysEx
+            if let NoteEvent::MidiCC {
+                channel,
+                cc: MOD_WHEEL,
+                value,
+                ..
+            } = event
+            {
+                let channel = normalize_input_channel(channel, one_based);
+                self.mod_wheel[(channel & 0x0f) as usize] = (value * 127.0).round() as u8;
+            }
+
+            match event {
+                // ── Note On → Program Change ──────────────────────────
+                NoteEvent::NoteOn {
+                    timing,
+                    voice_id,
+                    channel: input_channel,
+                    note,
+                    velocity,
+                } => {
+                    let over_cap = max_held_notes != 0 && self.held_notes >= max_held_notes;
+                    let starts_phrase = self.held_notes == 0;
+                    self.held_notes = self.held_notes.saturating_add(1);
+                    self.last_note_at = Some(now);
+
+                    let channel = normalize_input_channel(input_channel, one_based);
+                    if starts_phrase {
+                        self.phrase_channel = channel;
+                    }
+                    let forward = if channel + 1 == notes_passthrough_channel {
+                        true
+                    } else if channel + 1 == self.params.command_channel.value() as u8 {
+                        self.command(context, &settings, timing, note);
+                        self.ignored_notes.insert(channel, note);
+                        false
+                    } else if note as i32 == self.params.debug_dump_note.value() {
+                        self.debug_dump(context, &settings, timing, channel);
+                        self.ignored_notes.insert(channel, note);
+                        false
+                    } else if let Some(armed) = self.arm_toggle(note) {
+                        self.params.armed.store(armed, Ordering::Relaxed);
+                        self.ignored_notes.insert(channel, note);
+                        false
+                    } else if !self.params.armed.load(Ordering::Relaxed) {
+                        // Disarmed notes skip conversion entirely
+                        if !pass_through {
+                            self.ignored_notes.insert(channel, note);
+                        }
+                        pass_through
+                    } else if self.params.modifier_cc.value() >= 0
+                        && !self.modifier_down[(channel & 0x0f) as usize]
+                    {
+                        // Without the modifier the note just plays
+                        true
+                    } else if self.notes_to_ignore > 0 {
+                        self.notes_to_ignore -= 1;
+                        self.ignored_notes.insert(channel, note);
+                        false
+                    } else if over_cap {
+                        // Still counted as held, so its note-off balances out
+                        self.ignored_notes.insert(channel, note);
+                        false
+                    } else {
+                        if learning {
+                            self.learn_note(&settings, note);
+                        }
+                        let result =
+                            self.note_on(context, &settings, timing, channel, note, velocity);
+                        if result == NoteOnResult::Converted && split_note_channel != 0 {
+                            let split = &mut self.split_channels[(channel & 0x0f) as usize];
+                            split[(note & 0x7f) as usize] = split_note_channel;
+                            let split_note = NoteEvent::NoteOn {
+                                timing,
+                                voice_id,
+                                channel: split_note_channel - 1,
+                                note,
+                                velocity: self.forwarded_velocity(velocity),
+                            };
+                            self.send_split_note(context, now, split_note);
+                        }
+                        result == NoteOnResult::OutOfRange && forward_out_of_range
+                    };
+
+                    if forward {
+                        self.forwarded_notes.insert(channel, note);
+                        context.send_event(NoteEvent::NoteOn {
+                            timing,
+                            voice_id,
+                            channel: input_channel,
+                            note,
+                            velocity: self.forwarded_velocity(velocity),
+                        });
+                    }
+                    // Otherwise the note is consumed — not forwarded
+                }
+
+                // ── Note Off → consumed, optionally sends a program ───
+                NoteEvent::NoteOff {
+                    timing,
+                    voice_id,
+                    channel,
+                    note,
+                    velocity,
+                } => {
+                    let was_last_held = self.held_notes == 1;
+                    self.held_notes = self.held_notes.saturating_sub(1);
+                    self.last_note_at = Some(now);
+
+                    let channel = normalize_input_channel(channel, one_based);
+                    if self.resend_note == Some((channel, note)) {
+                        self.cancel_resend();
+                    }
+                    if self
+                        .pressure_base
+                        .is_some_and(|base| base.channel == channel && base.note == note)
+                    {
+                        self.pressure_base = None;
+                    }
+                    if all_notes_off_on_release && was_last_held {
+                        send_cc(
+                            context,
+                            timing,
+                            resolve_channel(settings.output_channel, channel),
+                            ALL_NOTES_OFF,
+                            0,
+                        );
+                    }
+                    let split_channel = std::mem::take(
+                        &mut self.split_channels[(channel & 0x0f) as usize][(note & 0x7f) as usize],
+                    );
+                    if split_channel != 0 {
+                        let split_note = NoteEvent::NoteOff {
+                            timing,
+                            voice_id,
+                            channel: split_channel - 1,
+                            note,
+                            velocity,
+                        };
+                        self.send_split_note(context, now, split_note);
+                    }
+                    let selected = self.selecting_notes.remove(channel, note);
+                    if self.ignored_notes.remove(channel, note) {
+                        // Dropped along with its note-on
+                    } else if self.forwarded_notes.remove(channel, note) {
+                        context.send_event(event);
+                    } else if self.release_top_note(context, &settings, timing, now, channel, note)
+                    {
+                        // Another held note took over
+                    } else if !selected {
+                        // Only notes that selected a program release to one
+                    } else if let Some(pc) =
+                        convert_note(&settings, self.follow_channel(channel), note)
+                    {
+                        let program = match note_off_behavior {
+                            NoteOffBehavior::Leave => None,
+                            NoteOffBehavior::RevertDefault => Some(0),
+                            NoteOffBehavior::SendOffProgram => Some(off_program),
+                            NoteOffBehavior::OffsetProgram => fit_program(
+                                pc.program as i16 + off_program_offset,
+                                settings.patch_count,
+                                settings.out_of_range_program,
+                            ),
+                        };
+                        if let Some(program) = program {
+                            self.send_program_change(
+                                context,
+                                timing,
+                                ProgramChange { program, ..pc },
+                            );
+                        }
+                    }
+                }
+
+                // ── Channel aftertouch → CC on the target patch ───────
+                NoteEvent::MidiChannelPressure {
+                    timing,
+                    channel,
+                    pressure,
+                } if pressure_to_cc => {
+                    let channel = normalize_input_channel(channel, one_based);
+                    context.send_event(NoteEvent::MidiCC {
+                        timing,
+                        channel: resolve_channel(settings.output_channel, channel),
+                        cc: pressure_cc,
+                        // Both are normalized, so 0–127 maps straight across
+                        value: pressure,
+                    });
+                }
+
+                // ── Repeated CC values → dropped (if enabled) ─────────
+                NoteEvent::MidiCC {
+                    channel, cc, value, ..
+                } if dedupe_cc => {
+                    // Compare at MIDI's 7-bit resolution
+                    let value = (value * 127.0).round() as u8;
+                    let last =
+                        &mut self.last_cc_values[(channel & 0x0f) as usize][(cc & 0x7f) as usize];
+                    if *last != value {
+                        *last = value;
+                        if pass_through {
+                            context.send_event(route_passthrough(event, passthrough_channel));
+                        }
+                    }
+                }
+
+                // ── CLAP modulation → pass through (if enabled) ───────
+                NoteEvent::PolyModulation { .. } => {
+                    if pass_through && self.params.forward_poly_modulation.value() {
+                        context.send_event(event);
+                    }
+                }
+                NoteEvent::MonoAutomation { .. } => {
+                    if pass_through && self.params.forward_mono_automation.value() {
+                        context.send_event(event);
+                    }
+                }
+
+                // ── Incoming Program Change → Bank Select + PC ────────
+                NoteEvent::MidiProgramChange {
+                    timing,
+                    channel,
+                    program,
+                } if self.params.expand_program_changes.value() => {
+                    let pc = ProgramChange {
+                        bank_msb: u8::try_from(self.params.expand_bank_msb.value()).ok(),
+                        bank_lsb: u8::try_from(self.params.expand_bank_lsb.value()).ok(),
+                        ..ProgramChange::new(resolve_channel(passthrough_channel, channel), program)
+                    };
+                    self.send_program_change(context, timing, pc);
+                }
+
+                // ── Everything else → pass through (if enabled) ───────
+                other => {
+                    if pass_through {
+                        context.send_event(route_passthrough(other, passthrough_channel));
+                    }
+                }
+            }
+
+            // Picks up anything the event itself queued for right now
+            self.flush_pending(now, buffer_start, context);
+        }
+
+        // Anything due later in this buffer still lands in this buffer
+        if buffer_end > buffer_start {
+            self.flush_pending(buffer_end - 1, buffer_start, context);
+        }
+        self.idle_program(context, &settings, buffer_start, buffer_end);
+        self.store_channel_programs();
+        self.sample_clock = buffer_end;
+        self.samples_since_init += buffer_end - buffer_start;
+    }
+
+    fn send_program_change(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        timing: u32,
+        pc: ProgramChange,
+    ) {
+        // Nothing goes out while the host is still loading the project
+        let grace_samples = self.ms_to_samples(self.params.startup_grace_ms.value());
+        if self.samples_since_init.saturating_add(timing as u64) < grace_samples {
+            return;
+        }
+
+        // Whichever feature picked it, the device only has so many patches
+        let patch_count = self.params.device_patch_count.value() as u8;
+        let policy = self.params.out_of_range_program.value();
+        let Some(program) = fit_program(pc.program as i16, patch_count, policy) else {
+            return;
+        };
+        let pc = ProgramChange { program, ..pc };
+
+        // The limits below only count changes that actually went out, so
+        // every check comes before anything is recorded
+        let channel = (pc.channel & 0x0f) as usize;
+        let now = self.sample_clock + timing as u64;
+        let gap = self.ms_to_samples(self.params.min_channel_interval_ms.value());
+        if let Some(last) =
+            self.last_sent_at[channel].filter(|&last| now < last.saturating_add(gap))
+        {
+            // Only the latest held-back change per channel goes out
+            self.pending
+                .retain(|p| p.source != PendingSource::Spacing || p.pc.channel != pc.channel);
+            self.enqueue(PendingProgramChange {
+                pc,
+                due: last.saturating_add(gap),
+                source: PendingSource::Spacing,
+            });
+            return;
+        }
+
+        let beat = self
+            .grid
+            .filter(|_| self.params.one_per_beat.value())
+            .map(|grid| grid.beat_index(timing));
+        if beat.is_some() && self.last_pc_beat == beat {
+            return;
+        }
+
+        // Going by the host's position keeps the window musical across
+        // tempo changes. A jump backwards, like a loop, never counts.
+        let window = self.params.dedupe_beats.value() as f64;
+        let beats = self
+            .grid
+            .filter(|_| window > 0.0)
+            .map(|grid| grid.beats_at(timing));
+        if let Some(beats) = beats {
+            if self.last_pc_beats[channel].is_some_and(|(program, at)| {
+                program == pc.program && (0.0..window).contains(&(beats - at))
+            }) {
+                return;
+            }
+        }
+
+        if self.params.collapse_same_buffer_duplicates.value()
+            && self.sent_this_buffer.contains(pc.channel, pc.program)
+        {
+            return;
+        }
+
+        // Frozen and muted changes still count as sent for everything that
+        // tracks the current program, but not for the limits. Frozen ones
+        // count for the dedupe too, so unfreezing doesn't repeat them.
+        let emit = !self.params.freeze.value();
+        let muted = self.channel_muted(pc.channel);
+        if emit && !muted {
+            self.last_sent_at[channel] = Some(now);
+            if beat.is_some() {
+                self.last_pc_beat = beat;
+            }
+        }
+        if !muted {
+            if let Some(beats) = beats {
+                self.last_pc_beats[channel] = Some((pc.program, beats));
+            }
+            self.sent_this_buffer.insert(pc.channel, pc.program);
+        }
+
+        if emit {
+            let options = self.emit_options();
+            if !muted {
+                emit_program_change(context, timing, pc, options);
+                #[cfg(feature = "logging")]
+                self.log_program_change(context, timing, pc);
+            }
+
+            let broadcast_channel = self.params.broadcast_channel.value() as u8;
+            if broadcast_channel != 0
+                && broadcast_channel - 1 != pc.channel
+                && !self.channel_muted(broadcast_channel - 1)
+            {
+                let broadcast = ProgramChange {
+                    channel: broadcast_channel - 1,
+                    ..pc
+                };
+                emit_program_change(context, timing, broadcast, options);
+            }
+
+            let monitor_channel = self.params.monitor_pc_channel.value() as u8;
+            if monitor_channel != 0 && !muted && !self.channel_muted(monitor_channel - 1) {
+                let offset = self.params.monitor_pc_offset.value() as u8;
+                context.send_event(NoteEvent::MidiProgramChange {
+                    timing,
+                    channel: monitor_channel - 1,
+                    program: pc.program.wrapping_add(offset) & 0x7f,
+                });
+            }
+        }
+        self.last_pc = Some(pc);
+        self.params
+            .last_program
+            .store(pc.program as u32, Ordering::Relaxed);
+        self.params
+            .last_channel
+            .store(pc.channel as u32, Ordering::Relaxed);
+        self.channel_programs[(pc.channel & 0x0f) as usize] = Some(pc.program);
+        self.channel_programs_dirty = true;
+    }
+
+    /// Writes the per-channel programs back to the persisted state, unless
+    /// the host is reading it right now, in which case the next buffer
+    /// tries again.
+    fn store_channel_programs(&mut self) {
+        if !self.channel_programs_dirty {
+            return;
+        }
+        if let Ok(mut saved) = self.params.channel_programs.try_write() {
+            *saved = self.channel_programs;
+            self.channel_programs_dirty = false;
+        }
+    }
+
+    /// Queues `pc` for the Program Change log. The file is written by the
+    /// background task, not here.
+    #[cfg(feature = "logging")]
+    fn log_program_change(
+        &self,
+        context: &mut impl ProcessContext<Self>,
+        timing: u32,
+        pc: ProgramChange,
+    ) {
+        let samples = self.samples_since_init.saturating_add(timing as u64);
+        let tempo = self
+            .grid
+            .map(|grid| 60.0 * self.sample_rate as f64 / grid.samples_per_beat);
+        context.execute_background(program_log::Task::Log(program_log::LogEntry {
+            seconds: samples as f64 / self.sample_rate as f64,
+            tempo,
+            channel: pc.channel,
+            program: pc.program,
+        }));
+    }
+
+    fn emit_options(&self) -> EmitOptions {
+        EmitOptions {
+            reset_controllers: self.params.reset_controllers_after_pc.value(),
+            program_cc: u8::try_from(self.params.program_cc.value()).ok(),
+        }
+    }
+
+    fn channel_muted(&self, channel: u8) -> bool {
+        self.params.muted_channels[(channel & 0x0f) as usize]
+            .muted
+            .value()
+    }
+
+    /// Sends the Program Changes saved with the project, if recall is on
+    /// and there are any, then the fixed program if that is sent on load.
+    /// This skips the startup grace period, restoring programs on load is
+    /// the point.
+    fn recall_on_load(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        settings: &ConvertSettings,
+    ) {
+        if self.params.freeze.value() {
+            return;
+        }
+
+        if self.params.recall_all_channels_on_load.value() {
+            for (channel, program) in self.channel_programs.into_iter().enumerate() {
+                if let Some(program) = program {
+                    let pc = ProgramChange::new(channel as u8, program.min(127));
+                    self.recall_program(context, pc);
+                }
+            }
+        } else if self.params.recall_program_on_load.value() {
+            if let Some(pc) = self.saved_program() {
+                self.recall_program(context, pc);
+            }
+        }
+
+        if self.params.send_fixed_program_on_load.value() {
+            let program = settings.fixed_program.and_then(|program| {
+                fit_program(program as i16, settings.patch_count, settings.out_of_range_program)
+            });
+            if let Some(program) = program {
+                // In Auto mode there is no note to follow, so it goes out on
+                // the channel of the last saved program
+                let channel = self.params.last_channel.load(Ordering::Relaxed).min(15) as u8;
+                let channel = resolve_channel(settings.output_channel, channel);
+                self.recall_program(context, ProgramChange::new(channel, program));
+            }
+        }
+    }
+
+    /// The last Program Change saved with the project, if one was sent.
+    fn saved_program(&self) -> Option<ProgramChange> {
+        let program = u8::try_from(self.params.last_program.load(Ordering::Relaxed)).ok()?;
+        let channel = self.params.last_channel.load(Ordering::Relaxed).min(15) as u8;
+        Some(ProgramChange::new(channel, program.min(127)))
+    }
+
+    fn recall_program(&mut self, context: &mut impl ProcessContext<Self>, pc: ProgramChange) {
+        if !self.channel_muted(pc.channel) {
+            emit_program_change(context, 0, pc, self.emit_options());
+            #[cfg(feature = "logging")]
+            self.log_program_change(context, 0, pc);
+        }
+        self.last_pc = Some(pc);
+    }
+
+    /// The channel a note on `channel` converts on: its own, or in per-phrase
+    /// mode the one the phrase started on.
+    fn follow_channel(&self, channel: u8) -> u8 {
+        match self.params.follow_mode.value() {
+            FollowMode::PerNote => channel,
+            FollowMode::PerPhrase => self.phrase_channel,
+        }
+    }
+
+    /// Handles an incoming note-on, with `channel` already normalized.
+    /// The note itself is never forwarded here.
+    fn note_on(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        settings: &ConvertSettings,
+        timing: u32,
+        channel: u8,
+        note: u8,
+        velocity: f32,
+    ) -> NoteOnResult {
+        let now = self.sample_clock + timing as u64;
+        let setlist_len = self.params.setlist_length.value() as usize;
+        if setlist_len > 0 && note == self.params.setlist_trigger_note.value() as u8 {
+            let program = self.advance_setlist(setlist_len);
+            let pc = ProgramChange::new(resolve_channel(settings.output_channel, channel), program);
+            self.send_program_change(context, timing, pc);
+            return NoteOnResult::Consumed;
+        }
+
+        // In two-note mode the bank zone only picks the bank, the next
+        // program note sends it along with its program
+        if self.params.two_note_bank_program.value() {
+            let bank_zone = self.params.bank_zone_low.value() as u8
+                ..=self.params.bank_zone_high.value() as u8;
+            if bank_zone.contains(&note) {
+                self.pending_bank = Some(note - bank_zone.start());
+                return NoteOnResult::Consumed;
+            }
+        }
+
+        let Some(pc) = convert_note(settings, self.follow_channel(channel), note) else {
+            return NoteOnResult::OutOfRange;
+        };
+        let note_cc = u8::try_from(self.params.note_cc.value()).ok();
+        let velocity_msb = self.velocity_bank_msb(velocity);
+        // A bank picked in two-note mode wins over the velocity's, and that
+        // one over the channel's
+        let pc = ProgramChange {
+            bank_msb: self
+                .pending_bank
+                .take()
+                .or(velocity_msb.map(|(msb, _)| msb))
+                .or(self.channel_bank(channel)),
+            bank_lsb: self
+                .velocity_bank(velocity)
+                .or(velocity_msb.and_then(|(_, lsb)| lsb)),
+            follow_cc: note_cc.map(|cc| {
+                let scale = self.params.note_cc_scale.value();
+                (cc, note_cc_value(note, scale, self.params.note_cc_offset.value()))
+            }),
+            mod_wheel: self
+                .params
+                .expression_patch_sysex
+                .value()
+                .then_some(self.mod_wheel[(channel & 0x0f) as usize]),
+            note_name: self.params.note_name_sysex.value().then_some(note),
+            ..pc
+        };
+
+        if self.params.require_double_press.value() {
+            let window = self.ms_to_samples(self.params.double_press_ms.value());
+            let last = std::mem::replace(&mut self.last_press[(note & 0x7f) as usize], now);
+            if last == NO_PRESS || now.saturating_sub(last) > window {
+                return NoteOnResult::Converted;
+            }
+            // A third press starts a new pair
+            self.last_press[(note & 0x7f) as usize] = NO_PRESS;
+        }
+
+        if self.params.track_top_note.value() {
+            let previous_top = self.top_notes.highest();
+            self.top_notes.insert(channel, note);
+            if previous_top.is_some_and(|(_, top)| top >= note) {
+                return NoteOnResult::Converted;
+            }
+        }
+
+        if self.params.once_per_note.value() {
+            let bit = 1u128 << (note & 0x7f);
+            if self.fired_notes & bit != 0 {
+                return NoteOnResult::Converted;
+            }
+            self.fired_notes |= bit;
+        }
+
+        let pc = if self.params.sequential_programs.value() {
+            ProgramChange {
+                program: self.advance_sequence(),
+                ..pc
+            }
+        } else {
+            self.spread_program(settings, pc)
+        };
+
+        self.selecting_notes.insert(channel, note);
+        self.schedule_program_change(context, timing, now, pc, Some((velocity, note)));
+        self.start_resend(now, channel, note, pc);
+        if self.params.pressure_selects_program.value() {
+            self.pressure_base = Some(PressureBase {
+                channel,
+                note,
+                pc,
+                step: 0,
+            });
+        }
+        NoteOnResult::Converted
+    }
+
+    /// Handles a note-off in top-note tracking mode, re-sending the program
+    /// of the new top note if the released one was on top. Returns whether
+    /// other tracked notes are still held, in which case the note-off
+    /// behavior doesn't apply.
+    fn release_top_note(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        settings: &ConvertSettings,
+        timing: u32,
+        now: u64,
+        channel: u8,
+        note: u8,
+    ) -> bool {
+        let previous_top = self.top_notes.highest();
+        if !self.top_notes.remove(channel, note) {
+            return false;
+        }
+        let Some(top) = self.top_notes.highest() else {
+            return false;
+        };
+
+        if previous_top == Some((channel, note)) {
+            if let Some(pc) = convert_note(settings, top.0, top.1) {
+                let pc = ProgramChange {
+                    bank_msb: self.channel_bank(top.0),
+                    ..pc
+                };
+                self.selecting_notes.insert(top.0, top.1);
+                self.schedule_program_change(context, timing, now, pc, None);
+            }
+        }
+
+        true
+    }
+
+    /// Moves `pc` to the next program in its spread cycle, advancing the
+    /// cycle of its mapped program.
+    fn spread_program(&mut self, settings: &ConvertSettings, pc: ProgramChange) -> ProgramChange {
+        let spread = self.params.spread.value() as u8;
+        if spread == 0 {
+            return pc;
+        }
+
+        let cycle = &mut self.spread_cycles[(pc.program & 0x7f) as usize];
+        let position = *cycle % (2 * spread + 1);
+        *cycle = position + 1;
+
+        // 0, +1, -1, +2, -2, ...
+        let distance = position.div_ceil(2) as i16;
+        let offset = if position % 2 == 1 { distance } else { -distance };
+        let program = pc.program as i16 + offset;
+        match fit_program(program, settings.patch_count, settings.out_of_range_program) {
+            Some(program) => ProgramChange { program, ..pc },
+            None => pc,
+        }
+    }
+
+    /// The Bank Select LSB `velocity` picks in velocity bank mode.
+    fn velocity_bank(&self, velocity: f32) -> Option<u8> {
+        if !self.params.velocity_bank_lsb.value() {
+            return None;
+        }
+
+        let low = self.params.velocity_bank_low.value() as u8;
+        let high = self.params.velocity_bank_high.value() as u8;
+        Some(scale_velocity(velocity, low, high))
+    }
+
+    /// The Bank Select MSB `velocity` picks in velocity MSB mode, with the
+    /// LSB that goes along with it.
+    fn velocity_bank_msb(&self, velocity: f32) -> Option<(u8, Option<u8>)> {
+        if !self.params.velocity_bank_msb.value() {
+            return None;
+        }
+
+        let low = self.params.velocity_msb_low.value() as u8;
+        let high = self.params.velocity_msb_high.value() as u8;
+        let lsb = u8::try_from(self.params.velocity_msb_lsb.value()).ok();
+        Some((scale_velocity(velocity, low, high), lsb))
+    }
+
+    /// The bank selected for `channel` in per-channel bank mode.
+    fn channel_bank(&self, channel: u8) -> Option<u8> {
+        self.params
+            .per_channel_bank
+            .value()
+            .then(|| self.params.channel_banks[(channel & 0x0f) as usize].bank.value() as u8)
+    }
+
+    /// Sends a converted Program Change now or hands it to whichever timing
+    /// feature is active: the pedal commit, coalescing, quantization or
+    /// morphing, in that order of precedence. `played` is the velocity and
+    /// note of the note-on that selected `pc`, if it came from one.
+    fn schedule_program_change(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        timing: u32,
+        now: u64,
+        pc: ProgramChange,
+        played: Option<(f32, u8)>,
+    ) {
+        let coalesce_samples = self.ms_to_samples(self.params.coalesce_ms.value());
+        let quantize_to = self.params.quantize_to.value();
+        if self.params.commit_on_pedal_release.value() {
+            // Latest wins until the pedal comes up
+            self.pedal_pc = Some(pc);
+        } else if coalesce_samples > 0 {
+            let due = now.saturating_add(coalesce_samples);
+            self.coalesce(context, timing, due, pc, played);
+        } else if self.grid.is_some() && quantize_to != QuantizeTo::Off {
+            self.quantize(quantize_to, timing, now, pc);
+        } else if self.params.morph_steps.value() {
+            let morph_samples = self.ms_to_samples(self.params.morph_ms.value());
+            self.morph(context, timing, now, morph_samples, pc);
+        } else {
+            self.send_program_change(context, timing, pc);
+        }
+    }
+
+    /// Returns the setlist program at the persisted index and moves the index
+    /// on to the next entry, wrapping after the last one.
+    fn advance_setlist(&self, setlist_len: usize) -> u8 {
+        let index = self.params.setlist_index.load(Ordering::Relaxed) as usize % setlist_len;
+        self.params
+            .setlist_index
+            .store(((index + 1) % setlist_len) as u32, Ordering::Relaxed);
+
+        self.params.setlist[index].program.value() as u8
+    }
+
+    /// Returns the persisted sequential program and moves it on by one,
+    /// wrapping after `sequence_max`.
+    fn advance_sequence(&self) -> u8 {
+        let len = self.params.sequence_max.value() as u32 + 1;
+        let program = self.params.sequence_index.load(Ordering::Relaxed) % len;
+        self.params
+            .sequence_index
+            .store((program + 1) % len, Ordering::Relaxed);
+
+        program as u8
+    }
+
+    /// Sends the trigger program when the trigger CC crosses the threshold
+    /// on its way up. Falling back below it re-arms the trigger.
+    fn trigger_cc(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        settings: &ConvertSettings,
+        timing: u32,
+        channel: u8,
+        value: f32,
+    ) {
+        let value = (value * 127.0).round() as u8;
+        let threshold = self.params.trigger_cc_threshold.value() as u8;
+        let last = std::mem::replace(
+            &mut self.trigger_cc_values[(channel & 0x0f) as usize],
+            value,
+        );
+        if last < threshold && value >= threshold {
+            let pc = ProgramChange::new(
+                resolve_channel(settings.output_channel, channel),
+                self.params.trigger_cc_program.value() as u8,
+            );
+            self.send_program_change(context, timing, pc);
+        }
+    }
+
+    /// Sends the program held back for the pedal when the sustain pedal on
+    /// `channel` is released.
+    fn sustain_pedal(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        timing: u32,
+        channel: u8,
+        value: f32,
+    ) {
+        // 64 and up counts as down
+        let down = value >= 0.5;
+        let was_down = std::mem::replace(&mut self.sustain_down[(channel & 0x0f) as usize], down);
+        if was_down && !down {
+            if let Some(pc) = self.pedal_pc.take() {
+                self.send_program_change(context, timing, pc);
+            }
+        }
+    }
+
+    /// Steps the held note's program by channel aftertouch, sending a
+    /// program whenever the pressure moves into another band.
+    fn pressure_program(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        settings: &ConvertSettings,
+        timing: u32,
+        channel: u8,
+        pressure: f32,
+    ) {
+        if !self.params.pressure_selects_program.value() {
+            return;
+        }
+        let Some(base) = self.pressure_base.filter(|base| base.channel == channel) else {
+            return;
+        };
+
+        let steps = self.params.pressure_program_steps.value() as u8;
+        let step = ((pressure.clamp(0.0, 1.0) * steps as f32) as u8).min(steps - 1);
+        if step == base.step {
+            return;
+        }
+        self.pressure_base = Some(PressureBase { step, ..base });
+
+        let program = base.pc.program as i16 + step as i16;
+        if let Some(program) =
+            fit_program(program, settings.patch_count, settings.out_of_range_program)
+        {
+            self.send_program_change(context, timing, ProgramChange { program, ..base.pc });
+        }
+    }
+
+    /// Runs the command `note` stands for on the command channel.
+    fn command(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        settings: &ConvertSettings,
+        timing: u32,
+        note: u8,
+    ) {
+        let step = match note % 12 {
+            0 => -1,
+            2 => 1,
+            4 => {
+                self.pending.clear();
+                self.pedal_pc = None;
+                self.cancel_resend();
+                for channel in 0..16 {
+                    send_cc(context, timing, channel, ALL_NOTES_OFF, 0);
+                }
+                return;
+            }
+            #[cfg(feature = "logging")]
+            5 => {
+                context.execute_background(program_log::Task::Export);
+                return;
+            }
+            _ => return,
+        };
+
+        // Until something was sent this session, steps from the program
+        // saved with the project, or from program 0 on the first channel
+        let last = self
+            .last_pc
+            .or_else(|| self.saved_program())
+            .unwrap_or_else(|| ProgramChange::new(resolve_channel(settings.output_channel, 0), 0));
+        let program = last.program as i16 + step;
+        if let Some(program) =
+            fit_program(program, settings.patch_count, settings.out_of_range_program)
+        {
+            let pc = ProgramChange::new(last.channel, program);
+            self.send_program_change(context, timing, pc);
+        }
+    }
+
+    /// Sends the debug dump for a dump note on `channel`. The note itself
+    /// isn't counted as held.
+    fn debug_dump(
+        &self,
+        context: &mut impl ProcessContext<Self>,
+        settings: &ConvertSettings,
+        timing: u32,
+        channel: u8,
+    ) {
+        let held_notes = self.held_notes.saturating_sub(1).min(127) as u8;
+        let output_channel = resolve_channel(settings.output_channel, channel);
+        send_cc(context, timing, output_channel, DEBUG_HELD_NOTES_CC, held_notes);
+        for (channel, program) in self.channel_programs.into_iter().enumerate() {
+            if let Some(program) = program {
+                send_cc(context, timing, channel as u8, DEBUG_PROGRAM_CC, program);
+            }
+        }
+    }
+
+    /// The armed state `note` switches to if it is the arm or disarm note.
+    /// A note set as both toggles between the two.
+    fn arm_toggle(&self, note: u8) -> Option<bool> {
+        let arm = self.params.arm_note.value() == note as i32;
+        let disarm = self.params.disarm_note.value() == note as i32;
+        match (arm, disarm) {
+            (true, true) => Some(!self.params.armed.load(Ordering::Relaxed)),
+            (true, false) => Some(true),
+            (false, true) => Some(false),
+            (false, false) => None,
+        }
+    }
+
+    /// Copies the persisted program map for this buffer, keeping the last
+    /// copy if the editor holds the lock.
+    fn refresh_program_map(&mut self) {
+        if let Ok(map) = self.params.program_map.try_read() {
+            for (program, slot) in self.program_map.iter_mut().enumerate() {
+                *slot = map.get(program).copied().unwrap_or(program as u8);
+            }
+        }
+    }
+
+    /// Copies the persisted note map for this buffer, like
+    /// `refresh_program_map`. Later entries for the same note win.
+    fn refresh_note_map(&mut self) {
+        if let Ok(map) = self.params.note_map.try_read() {
+            self.note_map = [None; 128];
+            for &(note, channel, program) in map.iter() {
+                self.note_map[(note & 0x7f) as usize] = Some(NoteMapEntry {
+                    channel: channel.min(16),
+                    program: program.min(127),
+                });
+            }
+        }
+    }
+
+    /// Widens the learned range to include `note`, as the range gate sees
+    /// it after the octave shift and quantization.
+    fn learn_note(&self, settings: &ConvertSettings, note: u8) {
+        let Some(note) = gated_note(settings, note) else {
+            return;
+        };
+        let (min_note, max_note) = match self.learned_range() {
+            Some((min_note, max_note)) => (min_note.min(note), max_note.max(note)),
+            None => (note, note),
+        };
+        self.params
+            .learned_range
+            .store((min_note as u32) << 8 | max_note as u32, Ordering::Relaxed);
+    }
+
+    fn learned_range(&self) -> Option<(u8, u8)> {
+        let range = self.params.learned_range.load(Ordering::Relaxed);
+        (range != NO_LEARNED_RANGE).then_some(((range >> 8 & 0x7f) as u8, (range & 0x7f) as u8))
+    }
+
+    /// The velocity a forwarded note-on goes out with.
+    fn forwarded_velocity(&self, velocity: f32) -> f32 {
+        (velocity * self.params.forward_velocity_scale.value())
+            .max(self.params.forward_velocity_floor.value())
+            .clamp(0.0, 1.0)
+    }
+
+    fn ms_to_samples(&self, ms: f32) -> u64 {
+        (ms / 1000.0 * self.sample_rate).round() as u64
+    }
+
+    /// Inserts `pending` into the queue, keeping it ordered by due time.
+    /// Entries beyond the preallocated capacity are dropped.
+    fn enqueue(&mut self, pending: PendingProgramChange) {
+        if self.pending.len() == self.pending.capacity() {
+            return;
+        }
+
+        let index = self.pending.partition_point(|p| p.due <= pending.due);
+        self.pending.insert(index, pending);
+    }
+
+    /// Sends every queued Program Change that is due by `now`.
+    fn flush_pending(
+        &mut self,
+        now: u64,
+        buffer_start: u64,
+        context: &mut impl ProcessContext<Self>,
+    ) {
+        while let Some(pending) = self.pending.first().copied() {
+            if pending.due > now {
+                break;
+            }
+
+            self.pending.remove(0);
+            // Anything that fell due before this buffer goes out at sample 0
+            let timing = pending.due.saturating_sub(buffer_start) as u32;
+            if let PendingSource::SplitNote(mut event) = pending.source {
+                if let NoteEvent::NoteOn { timing: at, .. }
+                | NoteEvent::NoteOff { timing: at, .. } = &mut event
+                {
+                    *at = timing;
+                }
+                context.send_event(event);
+                continue;
+            }
+            self.send_program_change(context, timing, pending.pc);
+
+            if pending.source == PendingSource::Resend {
+                if self.params.resend_interval_ms.value() > 0.0 {
+                    self.enqueue(PendingProgramChange {
+                        due: pending.due + self.resend_interval_samples(),
+                        ..pending
+                    });
+                } else {
+                    self.resend_note = None;
+                }
+            }
+        }
+    }
+
+    /// Sends a split note event `pc_lead_samples` late, the note-off as much
+    /// as the note-on so they stay in order. When the queue is full it goes
+    /// out right away.
+    fn send_split_note(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        now: u64,
+        event: NoteEvent<OutputSysEx>,
+    ) {
+        let lead = self.params.pc_lead_samples.value() as u64;
+        if lead == 0 || self.pending.len() == self.pending.capacity() {
+            context.send_event(event);
+            return;
+        }
+
+        self.enqueue(PendingProgramChange {
+            // Never sent, the note goes out in its place
+            pc: ProgramChange::new(0, 0),
+            due: now + lead,
+            source: PendingSource::SplitNote(event),
+        });
+    }
+
+    /// Starts re-sending `pc` while the note that selected it is held,
+    /// replacing any earlier note's resend timer.
+    fn start_resend(&mut self, now: u64, channel: u8, note: u8, pc: ProgramChange) {
+        self.cancel_resend();
+        if self.params.resend_interval_ms.value() <= 0.0 {
+            return;
+        }
+
+        self.resend_note = Some((channel, note));
+        self.enqueue(PendingProgramChange {
+            pc,
+            due: now + self.resend_interval_samples(),
+            source: PendingSource::Resend,
+        });
+    }
+
+    fn cancel_resend(&mut self) {
+        self.resend_note = None;
+        self.pending.retain(|p| p.source != PendingSource::Resend);
+    }
+
+    /// Sends the idle default program if the idle time runs out within this
+    /// buffer with no notes held. It fires once, the next note re-arms it.
+    fn idle_program(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        settings: &ConvertSettings,
+        buffer_start: u64,
+        buffer_end: u64,
+    ) {
+        let Ok(program) = u8::try_from(self.params.idle_default_program.value()) else {
+            return;
+        };
+        let Some(last_note_at) = self.last_note_at.filter(|_| self.held_notes == 0) else {
+            return;
+        };
+        let due = last_note_at + self.ms_to_samples(self.params.idle_ms.value());
+        if due >= buffer_end {
+            return;
+        }
+
+        self.last_note_at = None;
+        // Like the channel-change resend, Auto keeps the last program's channel
+        let channel = self.last_pc.map_or(0, |last| last.channel);
+        let pc = ProgramChange::new(resolve_channel(settings.output_channel, channel), program);
+        self.send_program_change(context, due.saturating_sub(buffer_start) as u32, pc);
+    }
+
+    /// The resend interval in samples, at least one so a resend can't fall
+    /// due again within the same flush.
+    fn resend_interval_samples(&self) -> u64 {
+        self.ms_to_samples(self.params.resend_interval_ms.value()).max(1)
+    }
+
+    /// Replaces the pending coalesced program with `pc`, unless
+    /// `coalesce_pick` keeps the pending one. A pending program on a
+    /// different channel is sent right away instead of being overwritten,
+    /// and the new one opens its own window. Programs that didn't come from
+    /// a note-on always replace the pending one.
+    fn coalesce(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        timing: u32,
+        due: u64,
+        pc: ProgramChange,
+        played: Option<(f32, u8)>,
+    ) {
+        let existing = self
+            .pending
+            .iter()
+            .position(|p| p.source == PendingSource::Coalesce);
+        match existing {
+            Some(index) if self.pending[index].pc.channel == pc.channel => {
+                let keep_pending = self.params.coalesce_pick.value() == CoalescePick::Loudest
+                    && played
+                        .zip(self.coalesced_note)
+                        .is_some_and(|(played, pending)| !is_louder(played, pending));
+                if !keep_pending {
+                    self.pending[index].pc = pc;
+                    self.coalesced_note = played;
+                }
+            }
+            _ => {
+                if let Some(index) = existing {
+                    let previous = self.pending.remove(index);
+                    self.send_program_change(context, timing, previous.pc);
+                }
+                self.enqueue(PendingProgramChange {
+                    pc,
+                    due,
+                    source: PendingSource::Coalesce,
+                });
+                self.coalesced_note = played;
+            }
+        }
+    }
+
+    /// Queues `pc` for the next grid line after `timing`. Only called while
+    /// the transport is running.
+    fn quantize(&mut self, quantize_to: QuantizeTo, timing: u32, now: u64, pc: ProgramChange) {
+        let Some(grid) = self.grid else {
+            return;
+        };
+
+        let beats = grid.beats_at(timing);
+        let beats = if quantize_to == QuantizeTo::Bar {
+            grid.next_bar(beats)
+        } else {
+            grid.next_beat(beats)
+        };
+        let buffer_start = now - timing as u64;
+        self.enqueue(PendingProgramChange {
+            pc,
+            due: buffer_start.saturating_add(grid.samples_until(beats)),
+            source: PendingSource::Quantize { beats },
+        });
+    }
+
+    /// Recomputes the due time of quantized Program Changes from the current
+    /// tempo and position. Once the transport stops there is no grid left to
+    /// wait for, so they go out right away.
+    fn retime_quantized(&mut self, buffer_start: u64) {
+        let mut retimed = false;
+        for pending in &mut self.pending {
+            if let PendingSource::Quantize { beats } = pending.source {
+                pending.due = match self.grid {
+                    Some(grid) => buffer_start.saturating_add(grid.samples_until(beats)),
+                    None => buffer_start,
+                };
+                retimed = true;
+            }
+        }
+
+        if retimed {
+            // Unstable sort, as the stable one may allocate
+            self.pending.sort_unstable_by_key(|p| p.due);
+        }
+    }
+
+    /// Steps from the last sent program to `pc.program`, sending every
+    /// program in between spread evenly over `morph_samples`. A morph still
+    /// in progress is handled per `morph_interrupt`.
+    fn morph(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        timing: u32,
+        now: u64,
+        morph_samples: u64,
+        pc: ProgramChange,
+    ) {
+        // The queue is ordered by due time, so this is the running morph's
+        // target
+        let running = self
+            .pending
+            .iter()
+            .rev()
+            .find(|p| p.source == PendingSource::Morph)
+            .copied();
+        match (running, self.params.morph_interrupt.value()) {
+            (Some(running), MorphInterrupt::FinishThenJump) => {
+                self.pending.retain(|p| p.source != PendingSource::AfterMorph);
+                return self.enqueue(PendingProgramChange {
+                    pc,
+                    due: running.due,
+                    source: PendingSource::AfterMorph,
+                });
+            }
+            (Some(running), MorphInterrupt::Queue) => {
+                if !self.enqueue_morph(running.pc.program, running.due, morph_samples, pc) {
+                    self.send_program_change(context, timing, pc);
+                }
+                return;
+            }
+            (Some(_), MorphInterrupt::Jump) => {
+                self.cancel_morph();
+                return self.send_program_change(context, timing, pc);
+            }
+            _ => self.cancel_morph(),
+        }
+
+        // Without a previous program on this channel there is nothing to
+        // morph from
+        let from = match self.last_pc {
+            Some(last) if last.channel == pc.channel => last.program,
+            _ => return self.send_program_change(context, timing, pc),
+        };
+        if from.abs_diff(pc.program) <= 1 {
+            return self.send_program_change(context, timing, pc);
+        }
+        if !self.enqueue_morph(from, now, morph_samples, pc) {
+            // No room to morph, but the target still goes out
+            self.send_program_change(context, timing, pc);
+        }
+    }
+
+    /// Queues the steps from `from` to `pc.program`, the first one due at
+    /// `start` and the target `morph_samples` later. The steps in between
+    /// are thinned out to what fits in the queue, so the target always
+    /// lands. With the queue full, a morph queued before this one gives up
+    /// its target, which this one starts from anyway. Returns `false` if
+    /// the queue is full of other changes, leaving the target to the caller.
+    fn enqueue_morph(
+        &mut self,
+        from: u8,
+        start: u64,
+        morph_samples: u64,
+        pc: ProgramChange,
+    ) -> bool {
+        let steps = from.abs_diff(pc.program) as u64;
+        if steps == 0 {
+            return true;
+        }
+        if self.pending.len() == self.pending.capacity() {
+            match self
+                .pending
+                .iter()
+                .rposition(|p| p.source == PendingSource::Morph)
+            {
+                Some(index) => {
+                    self.pending.remove(index);
+                }
+                None => return false,
+            }
+        }
+
+        // Every `stride`th step counting back from the target
+        let room = (self.pending.capacity() - self.pending.len()) as u64;
+        let stride = steps.div_ceil(room);
+        // The first step goes out right away, the target lands at the end
+        for step in (1..=steps).filter(|step| (steps - step).is_multiple_of(stride)) {
+            let program = if pc.program > from {
+                from + step as u8
+            } else {
+                from - step as u8
+            };
+            // Only the target program carries the note CC and name
+            let (follow_cc, note_name) = if step == steps {
+                (pc.follow_cc, pc.note_name)
+            } else {
+                (None, None)
+            };
+            self.enqueue(PendingProgramChange {
+                pc: ProgramChange {
+                    program,
+                    follow_cc,
+                    note_name,
+                    ..pc
+                },
+                due: start
+                    .saturating_add(morph_samples.saturating_mul(step - 1) / (steps - 1).max(1)),
+                source: PendingSource::Morph,
+            });
+        }
+        true
+    }
+
+    /// Drops the morph in progress, along with anything waiting for it.
+    fn cancel_morph(&mut self) {
+        self.pending
+            .retain(|p| p.source != PendingSource::Morph && p.source != PendingSource::AfterMorph);
+    }
+}
+
+/// Most same-timing events sorted together by `next_ordered_event`. A longer
+/// run is sorted in chunks of this size.
+const SAME_TIMING_RUN: usize = 64;
+
+/// Incoming events read ahead by `next_ordered_event`.
+struct OrderedEvents<S> {
+    /// The rest of the current same-timing run, already in order.
+    run: [Option<NoteEvent<S>>; SAME_TIMING_RUN],
+    /// Index of the next event in `run`.
+    next: usize,
+    /// The first event after the run, read to find where it ends.
+    after: Option<NoteEvent<S>>,
+}
+
+impl<S> Default for OrderedEvents<S> {
+    fn default() -> Self {
+        Self {
+            run: std::array::from_fn(|_| None),
+            next: 0,
+            after: None,
+        }
+    }
+}
+
+/// The next event to process. Unless `order` is `AsReceived`, every run of
+/// events at the same timing is read into `events` first and stably
+/// partitioned, so the note-offs or note-ons go ahead of everything else at
+/// their timing and each group keeps the host's order.
+fn next_ordered_event<P: Plugin>(
+    context: &mut impl ProcessContext<P>,
+    events: &mut OrderedEvents<P::SysExMessage>,
+    order: SameTimingOrder,
+) -> Option<NoteEvent<P::SysExMessage>> {
+    if let Some(event) = events.run.get_mut(events.next).and_then(Option::take) {
+        events.next += 1;
+        return Some(event);
+    }
+
+    let first = events.after.take().or_else(|| context.next_event())?;
+    if order == SameTimingOrder::AsReceived {
+        return Some(first);
+    }
+
+    let timing = first.timing();
+    events.run[0] = Some(first);
+    let mut len = 1;
+    while len < SAME_TIMING_RUN {
+        match context.next_event() {
+            Some(event) if event.timing() == timing => {
+                events.run[len] = Some(event);
+                len += 1;
+            }
+            after => {
+                events.after = after;
+                break;
+            }
+        }
+    }
+
+    // Moving each event that goes first to the end of the ones before it
+    // keeps both groups in order without allocating
+    let mut first_later = 0;
+    for index in 0..len {
+        if events.run[index]
+            .as_ref()
+            .is_some_and(|event| goes_first(event, order))
+        {
+            events.run[first_later..=index].rotate_right(1);
+            first_later += 1;
+        }
+    }
+
+    events.next = 1;
+    events.run[0].take()
+}
+
+fn goes_first<S>(event: &NoteEvent<S>, order: SameTimingOrder) -> bool {
+    match order {
+        SameTimingOrder::AsReceived => false,
+        SameTimingOrder::NoteOffsFirst => matches!(event, NoteEvent::NoteOff { .. }),
+        SameTimingOrder::NoteOnsFirst => matches!(event, NoteEvent::NoteOn { .. }),
+    }
+}
+
+/// Whether the (velocity, note) `played` beats `than` for
+/// `CoalescePick::Loudest`, the lower note winning a tie.
+fn is_louder((velocity, note): (f32, u8), (than_velocity, than_note): (f32, u8)) -> bool {
+    velocity > than_velocity || (velocity == than_velocity && note < than_note)
+}
+
+/// Moves `event` to `max_timing` if it comes later.
+fn clamp_timing<S>(mut event: NoteEvent<S>, max_timing: u32) -> NoteEvent<S> {
+    match &mut event {
+        NoteEvent::NoteOn { timing, .. }
+        | NoteEvent::NoteOff { timing, .. }
+        | NoteEvent::Choke { timing, .. }
+        | NoteEvent::VoiceTerminated { timing, .. }
+        | NoteEvent::PolyModulation { timing, .. }
+        | NoteEvent::MonoAutomation { timing, .. }
+        | NoteEvent::PolyPressure { timing, .. }
+        | NoteEvent::PolyVolume { timing, .. }
+        | NoteEvent::PolyPan { timing, .. }
+        | NoteEvent::PolyTuning { timing, .. }
+        | NoteEvent::PolyVibrato { timing, .. }
+        | NoteEvent::PolyExpression { timing, .. }
+        | NoteEvent::PolyBrightness { timing, .. }
+        | NoteEvent::MidiChannelPressure { timing, .. }
+        | NoteEvent::MidiPitchBend { timing, .. }
+        | NoteEvent::MidiCC { timing, .. }
+        | NoteEvent::MidiProgramChange { timing, .. }
+        | NoteEvent::MidiSysEx { timing, .. } => *timing = (*timing).min(max_timing),
+        _ => (),
+    }
+
+    event
+}
+
+/// Moves a passed-through channel message to `passthrough_channel` (0 keeps
+/// its channel). Events without a MIDI channel are returned unchanged.
+fn route_passthrough<S>(mut event: NoteEvent<S>, passthrough_channel: u8) -> NoteEvent<S> {
+    match &mut event {
+        NoteEvent::MidiCC { channel, .. }
+        | NoteEvent::MidiChannelPressure { channel, .. }
+        | NoteEvent::MidiPitchBend { channel, .. }
+        | NoteEvent::MidiProgramChange { channel, .. }
+        | NoteEvent::PolyPressure { channel, .. } => {
+            *channel = resolve_channel(passthrough_channel, *channel);
+        }
+        _ => (),
+    }
+
+    event
+}
+
+/// Sends `pc` to the host, preceded by its Bank Select and followed by its
+/// note CC if it has them. A Reset All Controllers, if enabled, goes in
+/// between so it doesn't clear the note CC. With a mod wheel value the
+/// program goes out as an expression patch SysEx instead. A note name comes
+/// last.
+fn emit_program_change<P: Plugin<SysExMessage = OutputSysEx>>(
+    context: &mut impl ProcessContext<P>,
+    timing: u32,
+    pc: ProgramChange,
+    options: EmitOptions,
+) {
+    // Bank Select has to precede the Program Change it applies to
+    if let Some(msb) = pc.bank_msb {
+        send_cc(context, timing, pc.channel, BANK_SELECT_MSB, msb);
+    }
+    if let Some(lsb) = pc.bank_lsb {
+        send_cc(context, timing, pc.channel, BANK_SELECT_LSB, lsb);
+    }
+    match (pc.mod_wheel, options.program_cc) {
+        (Some(mod_wheel), _) => context.send_event(NoteEvent::MidiSysEx {
+            timing,
+            message: OutputSysEx::ExpressionPatch(ExpressionPatch {
+                channel: pc.channel,
+                program: pc.program,
+                mod_wheel,
+            }),
+        }),
+        (None, Some(cc)) => send_cc(context, timing, pc.channel, cc, pc.program),
+        (None, None) => context.send_event(NoteEvent::MidiProgramChange {
+            timing,
+            channel: pc.channel,
+            program: pc.program,
+        }),
+    }
+    if options.reset_controllers {
+        send_cc(context, timing, pc.channel, RESET_ALL_CONTROLLERS, 0);
+    }
+    if let Some((cc, value)) = pc.follow_cc {
+        send_cc(context, timing, pc.channel, cc, value);
+    }
+    if let Some(note) = pc.note_name {
+        context.send_event(NoteEvent::MidiSysEx {
+            timing,
+            message: OutputSysEx::NoteName(NoteName { note }),
+        });
+    }
+}
+
+/// Spreads `velocity` (0–1) evenly across `low`–`high`. `high` may be
+/// below `low` to reverse the direction.
+fn scale_velocity(velocity: f32, low: u8, high: u8) -> u8 {
+    let (low, high) = (low as f32, high as f32);
+    (low + velocity.clamp(0.0, 1.0) * (high - low)).round() as u8
+}
+
+/// Sends a CC with a 7-bit `value`.
+fn send_cc<P: Plugin>(
+    context: &mut impl ProcessContext<P>,
+    timing: u32,
+    channel: u8,
+    cc: u8,
+    value: u8,
+) {
+    context.send_event(NoteEvent::MidiCC {
+        timing,
+        channel,
+        cc,
+        value: value as f32 / 127.0,
+    });
 }
 
 impl ClapPlugin for MidiNoteToPc {
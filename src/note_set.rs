@@ -0,0 +1,46 @@
+//! A fixed-size set of held notes, cheap enough to update on the audio thread.
+
+/// A set of (channel, note) pairs, stored as one bit per note per channel.
+/// Works just as well for any other 7-bit value, like programs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoteSet([u128; 16]);
+
+impl NoteSet {
+    pub fn insert(&mut self, channel: u8, note: u8) {
+        self.0[(channel & 0x0f) as usize] |= 1 << (note & 0x7f);
+    }
+
+    pub fn contains(&self, channel: u8, note: u8) -> bool {
+        self.0[(channel & 0x0f) as usize] & 1 << (note & 0x7f) != 0
+    }
+
+    /// Removes the pair, returning whether it was in the set.
+    pub fn remove(&mut self, channel: u8, note: u8) -> bool {
+        let bits = &mut self.0[(channel & 0x0f) as usize];
+        let mask = 1 << (note & 0x7f);
+        let present = *bits & mask != 0;
+        *bits &= !mask;
+
+        present
+    }
+
+    /// The pair with the highest note, the lowest channel winning ties.
+    pub fn highest(&self) -> Option<(u8, u8)> {
+        let mut highest = None;
+        for (channel, &bits) in self.0.iter().enumerate() {
+            if bits == 0 {
+                continue;
+            }
+            let note = 127 - bits.leading_zeros() as u8;
+            if highest.is_none_or(|(_, top)| note > top) {
+                highest = Some((channel as u8, note));
+            }
+        }
+
+        highest
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = [0; 16];
+    }
+}
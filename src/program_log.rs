@@ -0,0 +1,74 @@
+//! An optional log of the Program Changes that were sent, for analysing a
+//! performance afterwards. Entries are handed to a background task, so the
+//! audio thread never touches the file.
+
+use crate::smf;
+use nih_plug::prelude::nih_log;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// A sent Program Change, channel is 0-based.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogEntry {
+    /// Seconds since the plugin was initialized.
+    pub seconds: f64,
+    /// The host's tempo in BPM, if the transport was running.
+    pub tempo: Option<f64>,
+    pub channel: u8,
+    pub program: u8,
+}
+
+/// Work for the background thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Task {
+    /// Appends the entry to the log, and keeps it for the export.
+    Log(LogEntry),
+    /// Writes every entry kept so far to the MIDI file export.
+    Export,
+}
+
+/// Runs the background tasks. The entries kept for the export live here,
+/// on the background thread.
+pub fn executor() -> impl Fn(Task) + Send {
+    let session = Mutex::new(Vec::new());
+    move |task| {
+        let Ok(mut session) = session.lock() else {
+            return;
+        };
+        match task {
+            Task::Log(entry) => {
+                append(entry);
+                session.push(entry);
+            }
+            Task::Export => smf::write(&session),
+        }
+    }
+}
+
+/// The log file, `midi_note_to_pc.log` in the system's temp directory.
+pub fn log_path() -> PathBuf {
+    std::env::temp_dir().join("midi_note_to_pc.log")
+}
+
+/// Appends `entry` to the log as a `seconds,channel,program` line, with the
+/// channel 1-based. Runs on the background thread.
+pub fn append(entry: LogEntry) {
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path())
+        .and_then(|mut file| {
+            writeln!(
+                file,
+                "{:.6},{},{}",
+                entry.seconds,
+                entry.channel + 1,
+                entry.program
+            )
+        });
+    if let Err(err) = result {
+        nih_log!("Couldn't write to the Program Change log: {}", err);
+    }
+}
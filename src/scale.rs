@@ -0,0 +1,29 @@
+//! Keyboard layout helpers for mappings that look at which key a note is
+//! rather than its number.
+
+/// Number of white keys across the MIDI note range, C-1 to G9.
+pub const WHITE_KEY_COUNT: u8 = 75;
+
+/// Position of each pitch class among the white keys of its octave, `None`
+/// for black keys.
+const WHITE_KEY_IN_OCTAVE: [Option<u8>; 12] = [
+    Some(0),
+    None,
+    Some(1),
+    None,
+    Some(2),
+    Some(3),
+    None,
+    Some(4),
+    None,
+    Some(5),
+    None,
+    Some(6),
+];
+
+/// The white-key ordinal of `note` counting from note 0, so C-1 is 0 and
+/// D-1 is 1, or `None` for a black key.
+pub fn white_key_index(note: u8) -> Option<u8> {
+    let note = note & 0x7f;
+    WHITE_KEY_IN_OCTAVE[(note % 12) as usize].map(|index| note / 12 * 7 + index)
+}
@@ -0,0 +1,163 @@
+//! Standard MIDI File export of the Program Changes logged in a session, so
+//! a performance can be loaded back into a DAW. Like the log, the file is
+//! only ever written by the background task.
+
+use crate::program_log::LogEntry;
+use nih_plug::prelude::nih_log;
+use std::path::PathBuf;
+
+/// Ticks per quarter note.
+const TICKS_PER_BEAT: u16 = 480;
+
+/// Tempo in BPM used until the first Program Change sent with the
+/// transport running, and for a file without any.
+const DEFAULT_TEMPO: f64 = 120.0;
+
+/// The export, `midi_note_to_pc.mid` in the system's temp directory. Each
+/// export replaces the previous one.
+pub fn export_path() -> PathBuf {
+    std::env::temp_dir().join("midi_note_to_pc.mid")
+}
+
+/// Writes `entries` to the export file. Runs on the background thread.
+pub fn write(entries: &[LogEntry]) {
+    if let Err(err) = std::fs::write(export_path(), encode(entries)) {
+        nih_log!("Couldn't write the MIDI file export: {}", err);
+    }
+}
+
+/// Encodes `entries` as a format 0 file: the header, then a single track
+/// with the tempo, every Program Change at its time since the plugin was
+/// initialized, and the end of the track. Entries sent at a new tempo are
+/// preceded by a tempo change, and the time up to each entry is converted
+/// to ticks at the tempo in effect before it.
+pub fn encode(entries: &[LogEntry]) -> Vec<u8> {
+    let mut tempo = entries
+        .iter()
+        .find_map(|entry| entry.tempo)
+        .unwrap_or(DEFAULT_TEMPO);
+    let mut track = Vec::new();
+    write_tempo(&mut track, 0, tempo);
+
+    // Rounding each delta on its own would let the error add up
+    let (mut seconds, mut beats, mut ticks) = (0.0, 0.0, 0);
+    for entry in entries {
+        beats += (entry.seconds - seconds).max(0.0) * tempo / 60.0;
+        seconds = entry.seconds.max(seconds);
+        let at = (beats * TICKS_PER_BEAT as f64).round() as u64;
+        let mut delta = at - ticks;
+        ticks = at;
+
+        if let Some(new_tempo) = entry.tempo.filter(|&new_tempo| new_tempo != tempo) {
+            tempo = new_tempo;
+            write_tempo(&mut track, delta, tempo);
+            delta = 0;
+        }
+        write_delta(&mut track, delta);
+        track.extend([0xc0 | (entry.channel & 0x0f), entry.program & 0x7f]);
+    }
+    write_delta(&mut track, 0);
+    track.extend([0xff, 0x2f, 0x00]);
+
+    let mut file = Vec::with_capacity(22 + track.len());
+    file.extend(b"MThd");
+    file.extend(6u32.to_be_bytes());
+    // Format 0, one track
+    file.extend(0u16.to_be_bytes());
+    file.extend(1u16.to_be_bytes());
+    file.extend(TICKS_PER_BEAT.to_be_bytes());
+    file.extend(b"MTrk");
+    file.extend((track.len() as u32).to_be_bytes());
+    file.extend(track);
+    file
+}
+
+/// Appends a Set Tempo meta event for `tempo` BPM, `delta` ticks after the
+/// previous event.
+fn write_tempo(track: &mut Vec<u8>, delta: u64, tempo: f64) {
+    let micros_per_beat = (60_000_000.0 / tempo.max(1.0))
+        .round()
+        .min(0xff_ffff as f64) as u32;
+    write_delta(track, delta);
+    track.extend([0xff, 0x51, 0x03]);
+    track.extend(&micros_per_beat.to_be_bytes()[1..]);
+}
+
+/// Appends a delta time as a variable-length quantity, 7 bits per byte
+/// with the high bit set on all but the last. Anything past the format's
+/// 28 bits is clamped.
+fn write_delta(track: &mut Vec<u8>, delta: u64) {
+    let delta = delta.min(0x0fff_ffff) as u32;
+    for shift in [21, 14, 7] {
+        if delta >> shift != 0 {
+            track.push(0x80 | (delta >> shift & 0x7f) as u8);
+        }
+    }
+    track.push((delta & 0x7f) as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(seconds: f64, tempo: Option<f64>, program: u8) -> LogEntry {
+        LogEntry {
+            seconds,
+            tempo,
+            channel: 2,
+            program,
+        }
+    }
+
+    #[test]
+    fn file_has_a_header_a_track_and_an_end_of_track() {
+        let file = encode(&[entry(0.5, None, 10), entry(1.0, None, 11)]);
+        let track_len = file.len() as u32 - 22;
+        let mut expected = vec![];
+        expected.extend(b"MThd");
+        expected.extend([0, 0, 0, 6, 0, 0, 0, 1, 0x01, 0xe0]);
+        expected.extend(b"MTrk");
+        expected.extend(track_len.to_be_bytes());
+        // 120 BPM, then 480 ticks to each program
+        expected.extend([0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20]);
+        expected.extend([0x83, 0x60, 0xc2, 10]);
+        expected.extend([0x83, 0x60, 0xc2, 11]);
+        expected.extend([0x00, 0xff, 0x2f, 0x00]);
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn ticks_follow_the_tempo_in_effect() {
+        // A beat at 60 BPM, then one at 120 BPM
+        let file = encode(&[
+            entry(1.0, Some(60.0), 1),
+            entry(2.0, Some(120.0), 2),
+            entry(2.5, None, 3),
+        ]);
+        let track = &file[22..];
+        assert_eq!(track[..7], [0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40]);
+        assert_eq!(track[7..11], [0x83, 0x60, 0xc2, 1]);
+        assert_eq!(
+            track[11..22],
+            [0x83, 0x60, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, 0x00, 0xc2, 2]
+        );
+        assert_eq!(track[22..26], [0x83, 0x60, 0xc2, 3]);
+    }
+
+    #[test]
+    fn an_empty_session_is_a_valid_file() {
+        let file = encode(&[]);
+        assert_eq!(file[..4], *b"MThd");
+        assert_eq!(file[14..22], [b'M', b'T', b'r', b'k', 0, 0, 0, 11]);
+        assert_eq!(file[file.len() - 4..], [0x00, 0xff, 0x2f, 0x00]);
+    }
+
+    #[test]
+    fn long_deltas_use_more_bytes() {
+        let mut track = Vec::new();
+        write_delta(&mut track, 0x7f);
+        write_delta(&mut track, 0x80);
+        write_delta(&mut track, 0x0fff_ffff);
+        assert_eq!(track, [0x7f, 0x81, 0x00, 0xff, 0xff, 0xff, 0x7f]);
+    }
+}
@@ -0,0 +1,221 @@
+//! The SysEx messages the plugin sends: the "expression patch", a program and
+//! the mod wheel position in one message for devices with a custom protocol
+//! that expects both together, and a note name as text for devices that can
+//! show it on their display.
+
+use nih_plug::prelude::{util, SysExMessage};
+
+/// SysEx start and end bytes.
+const SYSEX_START: u8 = 0xf0;
+const SYSEX_END: u8 = 0xf7;
+
+/// The non-commercial manufacturer ID, reserved for custom protocols.
+const MANUFACTURER_ID: u8 = 0x7d;
+
+/// Marks a text message. An expression patch's channel byte is always below
+/// this.
+const TEXT: u8 = 0x10;
+
+/// Longest note name, e.g. `C#-1`.
+const NOTE_NAME_LEN: usize = 4;
+
+/// Either SysEx message, as the plugin's `SysExMessage` type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputSysEx {
+    ExpressionPatch(ExpressionPatch),
+    NoteName(NoteName),
+}
+
+impl SysExMessage for OutputSysEx {
+    type Buffer = [u8; 8];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        ExpressionPatch::from_buffer(buffer)
+            .map(Self::ExpressionPatch)
+            .or_else(|| NoteName::from_buffer(buffer).map(Self::NoteName))
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let mut buffer = [0; 8];
+        let len = match self {
+            Self::ExpressionPatch(patch) => copy_message(patch.to_buffer(), &mut buffer),
+            Self::NoteName(name) => copy_message(name.to_buffer(), &mut buffer),
+        };
+        (buffer, len)
+    }
+}
+
+/// Copies a message into the start of `buffer`, returning its length.
+fn copy_message<const N: usize>((message, len): ([u8; N], usize), buffer: &mut [u8]) -> usize {
+    buffer[..len].copy_from_slice(&message[..len]);
+    len
+}
+
+/// The name of a MIDI note in ASCII, with middle C (60) as `C4`, and its
+/// length.
+pub fn note_name(note: u8) -> ([u8; NOTE_NAME_LEN], usize) {
+    let note = note & 0x7f;
+    let mut name = [0; NOTE_NAME_LEN];
+    let pitch = util::NOTES[(note % 12) as usize].as_bytes();
+    name[..pitch.len()].copy_from_slice(pitch);
+    let mut len = pitch.len();
+    let octave = (note / 12) as i8 - 1;
+    if octave < 0 {
+        name[len] = b'-';
+        len += 1;
+    }
+    name[len] = b'0' + octave.unsigned_abs();
+    (name, len + 1)
+}
+
+/// `F0 7D <channel> <program> <mod wheel> F7`, channel is 0-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExpressionPatch {
+    pub channel: u8,
+    pub program: u8,
+    /// Last CC 1 value on the channel.
+    pub mod_wheel: u8,
+}
+
+impl SysExMessage for ExpressionPatch {
+    type Buffer = [u8; 6];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        match *buffer {
+            [SYSEX_START, MANUFACTURER_ID, channel, program, mod_wheel, SYSEX_END]
+                if channel < 16 && program < 128 && mod_wheel < 128 =>
+            {
+                Some(Self {
+                    channel,
+                    program,
+                    mod_wheel,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let buffer = [
+            SYSEX_START,
+            MANUFACTURER_ID,
+            self.channel & 0x0f,
+            self.program & 0x7f,
+            self.mod_wheel & 0x7f,
+            SYSEX_END,
+        ];
+        (buffer, buffer.len())
+    }
+}
+
+/// `F0 7D 10 <name> F7`: the name of `note` as 2-4 ASCII characters, see
+/// [`note_name`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoteName {
+    pub note: u8,
+}
+
+impl SysExMessage for NoteName {
+    type Buffer = [u8; NOTE_NAME_LEN + 4];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        match buffer {
+            [SYSEX_START, MANUFACTURER_ID, TEXT, name @ .., SYSEX_END] => (0..128)
+                .find(|&note| {
+                    let (candidate, len) = note_name(note);
+                    candidate[..len] == *name
+                })
+                .map(|note| Self { note }),
+            _ => None,
+        }
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let (name, len) = note_name(self.note);
+        let mut buffer = [0; NOTE_NAME_LEN + 4];
+        buffer[..3].copy_from_slice(&[SYSEX_START, MANUFACTURER_ID, TEXT]);
+        buffer[3..3 + len].copy_from_slice(&name[..len]);
+        buffer[3 + len] = SYSEX_END;
+        (buffer, len + 4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_the_buffer() {
+        let patch = ExpressionPatch {
+            channel: 15,
+            program: 127,
+            mod_wheel: 64,
+        };
+        let (buffer, len) = patch.to_buffer();
+        assert_eq!(buffer[..len], [0xf0, 0x7d, 15, 127, 64, 0xf7]);
+        assert_eq!(ExpressionPatch::from_buffer(&buffer[..len]), Some(patch));
+    }
+
+    #[test]
+    fn other_messages_are_rejected() {
+        // Another manufacturer, bytes out of range, and the wrong length
+        let rejected: [&[u8]; 4] = [
+            &[0xf0, 0x41, 0, 0, 0, 0xf7],
+            &[0xf0, 0x7d, 16, 0, 0, 0xf7],
+            &[0xf0, 0x7d, 0, 128, 0, 0xf7],
+            &[0xf0, 0x7d, 0, 0, 0xf7],
+        ];
+        for buffer in rejected {
+            assert_eq!(ExpressionPatch::from_buffer(buffer), None);
+        }
+    }
+
+    #[test]
+    fn out_of_range_fields_are_masked() {
+        let patch = ExpressionPatch {
+            channel: 0x12,
+            program: 0x85,
+            mod_wheel: 0xff,
+        };
+        assert_eq!(patch.to_buffer().0, [0xf0, 0x7d, 0x02, 0x05, 0x7f, 0xf7]);
+    }
+
+    #[test]
+    fn note_names_use_c4_for_middle_c() {
+        let name = |note| {
+            let (name, len) = note_name(note);
+            String::from_utf8(name[..len].to_vec()).unwrap()
+        };
+        assert_eq!(name(60), "C4");
+        assert_eq!(name(61), "C#4");
+        assert_eq!(name(1), "C#-1");
+        assert_eq!(name(127), "G9");
+    }
+
+    #[test]
+    fn note_name_round_trips_through_the_buffer() {
+        let (buffer, len) = NoteName { note: 61 }.to_buffer();
+        assert_eq!(buffer[..len], [0xf0, 0x7d, 0x10, b'C', b'#', b'4', 0xf7]);
+        for note in 0..128 {
+            let (buffer, len) = NoteName { note }.to_buffer();
+            assert_eq!(
+                NoteName::from_buffer(&buffer[..len]),
+                Some(NoteName { note })
+            );
+        }
+    }
+
+    #[test]
+    fn both_messages_round_trip_through_the_output_buffer() {
+        let patch = OutputSysEx::ExpressionPatch(ExpressionPatch {
+            channel: 15,
+            program: 127,
+            mod_wheel: 64,
+        });
+        let (buffer, len) = patch.to_buffer();
+        assert_eq!(OutputSysEx::from_buffer(&buffer[..len]), Some(patch));
+        let name = OutputSysEx::NoteName(NoteName { note: 0 });
+        let (buffer, len) = name.to_buffer();
+        assert_eq!(OutputSysEx::from_buffer(&buffer[..len]), Some(name));
+    }
+}
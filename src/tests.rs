@@ -0,0 +1,2032 @@
+//! Tests that drive the plugin buffer by buffer through `process_events()`,
+//! with a mock host context standing in for the wrapper.
+//!
+//! The sample rate is 1 kHz, so one sample is one millisecond, and every
+//! buffer is `BUFFER` samples long.
+
+use super::*;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+const BUFFER: usize = 64;
+
+type Event = NoteEvent<OutputSysEx>;
+
+/// Feeds queued input events to the plugin and collects what it sends.
+#[derive(Default)]
+struct MockContext {
+    input: VecDeque<Event>,
+    output: Vec<Event>,
+    background_tasks: RefCell<Vec<<MidiNoteToPc as Plugin>::BackgroundTask>>,
+}
+
+impl ProcessContext<MidiNoteToPc> for MockContext {
+    fn plugin_api(&self) -> PluginApi {
+        PluginApi::Clap
+    }
+
+    fn execute_background(&self, task: <MidiNoteToPc as Plugin>::BackgroundTask) {
+        self.background_tasks.borrow_mut().push(task);
+    }
+
+    fn execute_gui(&self, _task: <MidiNoteToPc as Plugin>::BackgroundTask) {}
+
+    fn transport(&self) -> &Transport {
+        unreachable!("the grid is passed to process_events() directly")
+    }
+
+    fn next_event(&mut self) -> Option<Event> {
+        self.input.pop_front()
+    }
+
+    fn send_event(&mut self, event: Event) {
+        self.output.push(event);
+    }
+
+    fn set_latency_samples(&self, _samples: u32) {}
+
+    fn set_current_voice_capacity(&self, _capacity: u32) {}
+}
+
+impl InitContext<MidiNoteToPc> for MockContext {
+    fn plugin_api(&self) -> PluginApi {
+        PluginApi::Clap
+    }
+
+    fn execute(&self, _task: <MidiNoteToPc as Plugin>::BackgroundTask) {}
+
+    fn set_latency_samples(&self, _samples: u32) {}
+
+    fn set_current_voice_capacity(&self, _capacity: u32) {}
+}
+
+/// A plugin as the host would have it after `initialize()`.
+struct Harness {
+    plugin: MidiNoteToPc,
+    context: MockContext,
+}
+
+impl Harness {
+    fn new(params: MidiNoteToPcParams) -> Self {
+        Self {
+            plugin: MidiNoteToPc {
+                params: Arc::new(params),
+                sample_rate: 1000.0,
+                ..Default::default()
+            },
+            context: MockContext::default(),
+        }
+    }
+
+    /// Re-initializes the plugin the way the wrapper does, after restoring
+    /// any state.
+    fn initialize(&mut self) {
+        let buffer_config = BufferConfig {
+            sample_rate: 1000.0,
+            min_buffer_size: None,
+            max_buffer_size: BUFFER as u32,
+            process_mode: ProcessMode::Realtime,
+        };
+        let layout = AudioIOLayout::default();
+        assert!(self
+            .plugin
+            .initialize(&layout, &buffer_config, &mut self.context));
+        self.plugin.reset();
+    }
+
+    /// Processes one buffer with the transport stopped.
+    fn run(&mut self, events: impl IntoIterator<Item = Event>) -> Vec<Event> {
+        self.run_with_grid(events, None)
+    }
+
+    /// Processes one buffer at `grid`, returning everything sent during it.
+    fn run_with_grid(
+        &mut self,
+        events: impl IntoIterator<Item = Event>,
+        grid: Option<TransportGrid>,
+    ) -> Vec<Event> {
+        self.context.input.extend(events);
+        self.plugin.process_events(&mut self.context, BUFFER, grid);
+        std::mem::take(&mut self.context.output)
+    }
+}
+
+fn int(value: i32) -> IntParam {
+    IntParam::new(
+        "Test",
+        value,
+        IntRange::Linear {
+            min: -128,
+            max: 128,
+        },
+    )
+}
+
+fn float(value: f32) -> FloatParam {
+    FloatParam::new(
+        "Test",
+        value,
+        FloatRange::Linear {
+            min: 0.0,
+            max: 10000.0,
+        },
+    )
+}
+
+fn on() -> BoolParam {
+    BoolParam::new("Test", true)
+}
+
+fn off() -> BoolParam {
+    BoolParam::new("Test", false)
+}
+
+fn note_on(timing: u32, note: u8) -> Event {
+    note_on_channel(timing, 0, note)
+}
+
+fn note_on_channel(timing: u32, channel: u8, note: u8) -> Event {
+    NoteEvent::NoteOn {
+        timing,
+        voice_id: None,
+        channel,
+        note,
+        velocity: 1.0,
+    }
+}
+
+fn note_off(timing: u32, note: u8) -> Event {
+    note_off_channel(timing, 0, note)
+}
+
+fn note_off_channel(timing: u32, channel: u8, note: u8) -> Event {
+    NoteEvent::NoteOff {
+        timing,
+        voice_id: None,
+        channel,
+        note,
+        velocity: 0.0,
+    }
+}
+
+/// The (timing, channel, program) of every Program Change in `events`.
+fn program_changes(events: &[Event]) -> Vec<(u32, u8, u8)> {
+    events
+        .iter()
+        .filter_map(|event| match *event {
+            NoteEvent::MidiProgramChange {
+                timing,
+                channel,
+                program,
+            } => Some((timing, channel, program)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A 4/4 grid at 600 BPM, so a beat is 100 samples, `buffers` buffers in.
+fn grid(buffers: usize) -> TransportGrid {
+    TransportGrid {
+        start_beats: (buffers * BUFFER) as f64 / 100.0,
+        samples_per_beat: 100.0,
+        bar_start_beats: None,
+        beats_per_bar: 4.0,
+    }
+}
+
+#[test]
+fn coalesced_program_lands_in_a_later_buffer() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        coalesce_ms: float(100.0),
+        ..Default::default()
+    });
+
+    assert_eq!(program_changes(&harness.run([note_on(10, 60)])), []);
+    assert_eq!(program_changes(&harness.run([])), [(46, 0, 60)]);
+    assert_eq!(program_changes(&harness.run([])), []);
+}
+
+#[test]
+fn morph_steps_land_in_later_buffers() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        morph_steps: on(),
+        morph_ms: float(100.0),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_on(32, 63)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (32, 0, 61)]);
+    assert_eq!(program_changes(&harness.run([])), [(18, 0, 62)]);
+    assert_eq!(program_changes(&harness.run([])), [(4, 0, 63)]);
+}
+
+#[test]
+fn spaced_program_lands_in_a_later_buffer() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        min_channel_interval_ms: float(100.0),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_on(10, 61)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60)]);
+    assert_eq!(program_changes(&harness.run([])), [(36, 0, 61)]);
+}
+
+#[test]
+fn resends_land_in_later_buffers() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        resend_interval_ms: float(100.0),
+        ..Default::default()
+    });
+
+    assert_eq!(
+        program_changes(&harness.run([note_on(0, 60)])),
+        [(0, 0, 60)]
+    );
+    assert_eq!(program_changes(&harness.run([])), [(36, 0, 60)]);
+    assert_eq!(program_changes(&harness.run([])), []);
+    assert_eq!(program_changes(&harness.run([])), [(8, 0, 60)]);
+    // Releasing the note stops the resends
+    harness.run([note_off(0, 60)]);
+    assert_eq!(program_changes(&harness.run([])), []);
+    assert_eq!(program_changes(&harness.run([])), []);
+}
+
+#[test]
+fn quantized_program_lands_on_the_beat_in_a_later_buffer() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        quantize_to: EnumParam::new("Test", QuantizeTo::Beat),
+        ..Default::default()
+    });
+
+    let sent = harness.run_with_grid([note_on(10, 60)], Some(grid(0)));
+    assert_eq!(program_changes(&sent), []);
+    let sent = harness.run_with_grid([], Some(grid(1)));
+    assert_eq!(program_changes(&sent), [(36, 0, 60)]);
+}
+
+#[test]
+fn quantized_program_goes_out_when_the_transport_stops() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        quantize_to: EnumParam::new("Test", QuantizeTo::Bar),
+        ..Default::default()
+    });
+
+    harness.run_with_grid([note_on(10, 60)], Some(grid(0)));
+    assert_eq!(program_changes(&harness.run([])), [(0, 0, 60)]);
+}
+
+#[test]
+fn coalescing_keeps_only_the_latest_note() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        coalesce_ms: float(50.0),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_on(10, 61), note_on(20, 62)]);
+    assert_eq!(program_changes(&sent), [(50, 0, 62)]);
+}
+
+#[test]
+fn coalescing_sends_the_pending_program_on_a_channel_switch() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        coalesce_ms: float(20.0),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on_channel(0, 0, 60), note_on_channel(10, 1, 61)]);
+    assert_eq!(program_changes(&sent), [(10, 0, 60), (30, 1, 61)]);
+}
+
+#[test]
+fn note_off_behaviors() {
+    let released = |note_off_behavior| {
+        let mut harness = Harness::new(MidiNoteToPcParams {
+            note_off_behavior: EnumParam::new("Test", note_off_behavior),
+            off_program: int(100),
+            off_program_offset: int(-2),
+            ..Default::default()
+        });
+        harness.run([note_on(0, 60)]);
+        program_changes(&harness.run([note_off(5, 60)]))
+    };
+
+    assert_eq!(released(NoteOffBehavior::Leave), []);
+    assert_eq!(released(NoteOffBehavior::RevertDefault), [(5, 0, 0)]);
+    assert_eq!(released(NoteOffBehavior::SendOffProgram), [(5, 0, 100)]);
+    assert_eq!(released(NoteOffBehavior::OffsetProgram), [(5, 0, 58)]);
+}
+
+#[test]
+fn out_of_range_note_offs_send_nothing() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        note_off_behavior: EnumParam::new("Test", NoteOffBehavior::RevertDefault),
+        max_note: int(60),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 100)]);
+    assert_eq!(program_changes(&harness.run([note_off(5, 100)])), []);
+}
+
+#[test]
+fn changing_the_output_channel_resends_the_program() {
+    let params = |output_channel| MidiNoteToPcParams {
+        output_channel: int(output_channel),
+        resend_on_channel_change: on(),
+        ..Default::default()
+    };
+    let mut harness = Harness::new(params(0));
+
+    let sent = harness.run([note_on_channel(10, 2, 60)]);
+    assert_eq!(program_changes(&sent), [(10, 2, 60)]);
+    harness.plugin.params = Arc::new(params(5));
+    assert_eq!(program_changes(&harness.run([])), [(0, 4, 60)]);
+    assert_eq!(program_changes(&harness.run([])), []);
+    // Back in Auto mode the program stays where it was last sent
+    harness.plugin.params = Arc::new(params(0));
+    assert_eq!(program_changes(&harness.run([])), [(0, 4, 60)]);
+}
+
+#[test]
+fn morph_spreads_the_steps_evenly() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        morph_steps: on(),
+        morph_ms: float(60.0),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 10)]);
+    let sent = harness.run([note_on(0, 14)]);
+    assert_eq!(
+        program_changes(&sent),
+        [(0, 0, 11), (20, 0, 12), (40, 0, 13), (60, 0, 14)]
+    );
+    let sent = harness.run([note_on(0, 11)]);
+    assert_eq!(
+        program_changes(&sent),
+        [(0, 0, 13), (30, 0, 12), (60, 0, 11)]
+    );
+}
+
+#[test]
+fn morph_jumps_straight_to_a_neighbouring_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        morph_steps: on(),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 10)]);
+    assert_eq!(
+        program_changes(&harness.run([note_on(5, 11)])),
+        [(5, 0, 11)]
+    );
+}
+
+/// The programs sent after a morph from 10 to 20 is interrupted by 12,
+/// run until nothing is left pending.
+fn interrupted_morph(morph_interrupt: MorphInterrupt) -> Vec<u8> {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        morph_steps: on(),
+        morph_ms: float(100.0),
+        morph_interrupt: EnumParam::new("Test", morph_interrupt),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 10)]);
+    let sent = harness.run([note_on(0, 20)]);
+    assert_eq!(
+        program_changes(&sent),
+        [
+            (0, 0, 11),
+            (11, 0, 12),
+            (22, 0, 13),
+            (33, 0, 14),
+            (44, 0, 15),
+            (55, 0, 16)
+        ]
+    );
+    let mut sent = harness.run([note_on(0, 12)]);
+    while !harness.plugin.pending.is_empty() {
+        sent.extend(harness.run([]));
+    }
+    program_changes(&sent)
+        .into_iter()
+        .map(|(_, _, program)| program)
+        .collect()
+}
+
+#[test]
+fn restarted_morph_steps_back_from_where_it_got_to() {
+    assert_eq!(interrupted_morph(MorphInterrupt::Restart), [15, 14, 13, 12]);
+}
+
+#[test]
+fn jumping_abandons_the_morph() {
+    assert_eq!(interrupted_morph(MorphInterrupt::Jump), [12]);
+}
+
+#[test]
+fn finish_then_jump_sends_the_new_program_after_the_morph() {
+    assert_eq!(
+        interrupted_morph(MorphInterrupt::FinishThenJump),
+        [17, 18, 19, 20, 12]
+    );
+}
+
+#[test]
+fn morph_sends_its_target_when_the_queue_is_full() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        morph_steps: on(),
+        morph_ms: float(100.0),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 10)]);
+    // Changes held back far in the future, none of them a morph
+    while harness.plugin.pending.len() < harness.plugin.pending.capacity() {
+        harness.plugin.pending.push(PendingProgramChange {
+            pc: ProgramChange::new(5, 0),
+            due: u64::MAX,
+            source: PendingSource::Spacing,
+        });
+    }
+    assert_eq!(
+        program_changes(&harness.run([note_on(3, 20)])),
+        [(3, 0, 20)]
+    );
+}
+
+/// The (timing, channel, cc, 7-bit value) of every CC in `events`.
+fn ccs(events: &[Event]) -> Vec<(u32, u8, u8, u8)> {
+    events
+        .iter()
+        .filter_map(|event| match *event {
+            NoteEvent::MidiCC {
+                timing,
+                channel,
+                cc,
+                value,
+            } => Some((timing, channel, cc, (value * 127.0).round() as u8)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn aftertouch_drives_the_pressure_cc() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        output_channel: int(3),
+        pressure_to_cc: on(),
+        pressure_cc: int(11),
+        ..Default::default()
+    });
+
+    let sent = harness.run([NoteEvent::MidiChannelPressure {
+        timing: 7,
+        channel: 0,
+        pressure: 1.0,
+    }]);
+    assert_eq!(ccs(&sent), [(7, 2, 11, 127)]);
+    assert!(!sent
+        .iter()
+        .any(|event| matches!(event, NoteEvent::MidiChannelPressure { .. })));
+}
+
+#[test]
+fn setlist_trigger_steps_through_the_entries() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        setlist_length: int(3),
+        setlist_trigger_note: int(90),
+        ..Default::default()
+    });
+
+    let presses = (0..4).map(|press| note_on(press * 10, 90));
+    let programs: Vec<_> = program_changes(&harness.run(presses))
+        .into_iter()
+        .map(|(_, _, program)| program)
+        .collect();
+    assert_eq!(programs, [0, 1, 2, 0]);
+    // The index is persisted, so it survives a reset
+    harness.plugin.reset();
+    assert_eq!(program_changes(&harness.run([note_on(0, 90)])), [(0, 0, 1)]);
+}
+
+/// The (timing, channel, note) of every note-on in `events`.
+fn note_ons(events: &[Event]) -> Vec<(u32, u8, u8)> {
+    events
+        .iter()
+        .filter_map(|event| match *event {
+            NoteEvent::NoteOn {
+                timing,
+                channel,
+                note,
+                ..
+            } => Some((timing, channel, note)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The (timing, channel, note) of every note-off in `events`.
+fn note_offs(events: &[Event]) -> Vec<(u32, u8, u8)> {
+    events
+        .iter()
+        .filter_map(|event| match *event {
+            NoteEvent::NoteOff {
+                timing,
+                channel,
+                note,
+                ..
+            } => Some((timing, channel, note)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn out_of_range_notes_are_forwarded_when_enabled() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        max_note: int(60),
+        forward_out_of_range_notes: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([
+        note_on(0, 60),
+        note_on(1, 61),
+        note_off(2, 60),
+        note_off(3, 61),
+    ]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60)]);
+    assert_eq!(note_ons(&sent), [(1, 0, 61)]);
+    assert_eq!(note_offs(&sent), [(3, 0, 61)]);
+}
+
+#[test]
+fn out_of_range_notes_are_dropped_by_default() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        max_note: int(60),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 61), note_off(1, 61)]);
+    assert_eq!(note_ons(&sent), []);
+    assert_eq!(note_offs(&sent), []);
+}
+
+fn cc(timing: u32, cc: u8, value: u8) -> Event {
+    NoteEvent::MidiCC {
+        timing,
+        channel: 0,
+        cc,
+        value: value as f32 / 127.0,
+    }
+}
+
+#[test]
+fn repeated_cc_values_are_dropped() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        dedupe_cc: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([cc(0, 7, 64), cc(1, 7, 64), cc(2, 7, 65), cc(3, 10, 65)]);
+    assert_eq!(ccs(&sent), [(0, 0, 7, 64), (2, 0, 7, 65), (3, 0, 10, 65)]);
+    // The last value is remembered across buffers, until a reset
+    assert_eq!(ccs(&harness.run([cc(0, 7, 65)])), []);
+    harness.plugin.reset();
+    assert_eq!(ccs(&harness.run([cc(0, 7, 65)])), [(0, 0, 7, 65)]);
+}
+
+#[test]
+fn held_note_count_ignores_unmatched_note_offs() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        debug_dump_note: int(120),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_off(0, 60), note_on(1, 61), note_on(2, 120)]);
+    assert_eq!(ccs(&sent)[0], (2, 0, DEBUG_HELD_NOTES_CC, 1));
+}
+
+#[test]
+fn nothing_is_sent_during_the_startup_grace_period() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        startup_grace_ms: float(100.0),
+        ..Default::default()
+    });
+
+    assert_eq!(program_changes(&harness.run([note_on(0, 60)])), []);
+    let sent = harness.run([note_on(35, 61), note_on(36, 62)]);
+    assert_eq!(program_changes(&sent), [(36, 0, 62)]);
+}
+
+#[test]
+fn bank_zone_note_sets_the_bank_for_the_next_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        two_note_bank_program: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 102), note_on(5, 60)]);
+    assert_eq!(ccs(&sent), [(5, 0, BANK_SELECT_MSB, 2)]);
+    assert_eq!(program_changes(&sent), [(5, 0, 60)]);
+    // Used up by that program
+    let sent = harness.run([note_on(0, 61)]);
+    assert_eq!(ccs(&sent), []);
+    assert_eq!(program_changes(&sent), [(0, 0, 61)]);
+}
+
+/// Runs buffers from `first` on along `grid()` until one sends a Program
+/// Change, returning the buffer and its Program Changes.
+fn run_until_program_change(harness: &mut Harness, first: usize) -> (usize, Vec<(u32, u8, u8)>) {
+    for buffer in first..first + 100 {
+        let sent = program_changes(&harness.run_with_grid([], Some(grid(buffer))));
+        if !sent.is_empty() {
+            return (buffer, sent);
+        }
+    }
+    panic!("no Program Change within 100 buffers");
+}
+
+#[test]
+fn quantized_program_waits_for_the_next_bar() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        quantize_to: EnumParam::new("Test", QuantizeTo::Bar),
+        ..Default::default()
+    });
+
+    harness.run_with_grid([note_on(10, 60)], Some(grid(0)));
+    // Bar 2 starts at sample 400
+    assert_eq!(
+        run_until_program_change(&mut harness, 1),
+        (6, vec![(16, 0, 60)])
+    );
+}
+
+#[test]
+fn quantized_program_on_the_beat_goes_out_right_away() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        quantize_to: EnumParam::new("Test", QuantizeTo::Beat),
+        ..Default::default()
+    });
+
+    let sent = harness.run_with_grid([note_on(0, 60)], Some(grid(0)));
+    assert_eq!(program_changes(&sent), [(0, 0, 60)]);
+}
+
+#[test]
+fn passed_through_events_move_to_their_own_channel() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        output_channel: int(2),
+        passthrough_channel: int(5),
+        ..Default::default()
+    });
+
+    let sent = harness.run([cc(0, 7, 100), note_on(1, 60)]);
+    assert_eq!(ccs(&sent), [(0, 4, 7, 100)]);
+    assert_eq!(program_changes(&sent), [(1, 1, 60)]);
+}
+
+#[test]
+fn first_notes_after_a_reset_are_ignored() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        ignore_first_notes: int(2),
+        ..Default::default()
+    });
+    harness.plugin.reset();
+
+    let sent = harness.run([note_on(0, 60), note_on(1, 61), note_on(2, 62)]);
+    assert_eq!(program_changes(&sent), [(2, 0, 62)]);
+    // Their note-offs are swallowed along with them
+    let sent = harness.run([note_off(0, 60), note_off(1, 61)]);
+    assert_eq!(note_offs(&sent), []);
+}
+
+#[test]
+fn trigger_cc_fires_on_the_way_up() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        trigger_cc: int(20),
+        trigger_cc_threshold: int(64),
+        trigger_cc_program: int(42),
+        pass_through: off(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([
+        cc(0, 20, 10),
+        cc(1, 20, 70),
+        cc(2, 20, 80),
+        cc(3, 20, 20),
+        cc(4, 20, 64),
+        cc(5, 21, 127),
+    ]);
+    assert_eq!(program_changes(&sent), [(1, 0, 42), (4, 0, 42)]);
+}
+
+#[test]
+fn program_changes_are_copied_to_the_broadcast_channel() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        broadcast_channel: int(10),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on_channel(0, 0, 60), note_on_channel(1, 9, 61)]);
+    // Not twice on the broadcast channel itself
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (0, 9, 60), (1, 9, 61)]);
+}
+
+#[test]
+fn same_program_is_sent_once_per_buffer() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        collapse_same_buffer_duplicates: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_on(1, 61), note_on(2, 60)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (1, 0, 61)]);
+    assert_eq!(
+        program_changes(&harness.run([note_on(0, 60)])),
+        [(0, 0, 60)]
+    );
+}
+
+#[test]
+fn clap_modulation_pass_through_toggles() {
+    let events = [
+        NoteEvent::PolyModulation {
+            timing: 0,
+            voice_id: 1,
+            poly_modulation_id: 2,
+            normalized_offset: 0.5,
+        },
+        NoteEvent::MonoAutomation {
+            timing: 1,
+            poly_modulation_id: 2,
+            normalized_value: 0.5,
+        },
+    ];
+
+    let mut harness = Harness::new(MidiNoteToPcParams::default());
+    assert_eq!(harness.run(events), events);
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        forward_poly_modulation: off(),
+        ..Default::default()
+    });
+    assert_eq!(harness.run(events), events[1..]);
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        forward_mono_automation: off(),
+        ..Default::default()
+    });
+    assert_eq!(harness.run(events), events[..1]);
+}
+
+#[test]
+fn each_note_fires_once_until_a_reset() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        once_per_note: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_on(1, 61), note_on(2, 60)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (1, 0, 61)]);
+    assert_eq!(program_changes(&harness.run([note_on(0, 61)])), []);
+    harness.plugin.reset();
+    assert_eq!(
+        program_changes(&harness.run([note_on(0, 61)])),
+        [(0, 0, 61)]
+    );
+}
+
+#[test]
+fn forwarded_velocity_is_scaled_and_clamped() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        forward_out_of_range_notes: on(),
+        max_note: int(0),
+        forward_velocity_scale: float(0.5),
+        ..Default::default()
+    });
+
+    let velocity = |events: Vec<Event>| match events[..] {
+        [NoteEvent::NoteOn { velocity, .. }] => velocity,
+        _ => panic!("expected one note-on, got {events:?}"),
+    };
+    assert_eq!(velocity(harness.run([note_on(0, 60)])), 0.5);
+    harness.plugin.params = Arc::new(MidiNoteToPcParams {
+        forward_out_of_range_notes: on(),
+        max_note: int(0),
+        forward_velocity_scale: float(2.0),
+        ..Default::default()
+    });
+    assert_eq!(velocity(harness.run([note_on(0, 61)])), 1.0);
+}
+
+#[test]
+fn each_input_channel_selects_its_own_bank() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        output_channel: int(1),
+        per_channel_bank: on(),
+        channel_banks: std::array::from_fn(|channel| ChannelBankParams {
+            bank: int(channel as i32 + 10),
+        }),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on_channel(0, 2, 60), note_on_channel(1, 5, 61)]);
+    assert_eq!(
+        ccs(&sent),
+        [(0, 0, BANK_SELECT_MSB, 12), (1, 0, BANK_SELECT_MSB, 15)]
+    );
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (1, 0, 61)]);
+}
+
+#[test]
+fn all_notes_off_follows_the_last_release() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        note_off_sends_all_notes_off: on(),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 60), note_on(1, 62)]);
+    assert_eq!(ccs(&harness.run([note_off(0, 60)])), []);
+    assert_eq!(
+        ccs(&harness.run([note_off(3, 62)])),
+        [(3, 0, ALL_NOTES_OFF, 0)]
+    );
+    // A stray note-off with nothing held doesn't send another
+    assert_eq!(ccs(&harness.run([note_off(0, 62)])), []);
+}
+
+#[test]
+fn note_ons_beyond_the_held_note_cap_are_dropped() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        max_held_notes: int(2),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_on(1, 61), note_on(2, 62)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (1, 0, 61)]);
+    // The dropped note's note-off still frees its slot
+    harness.run([note_off(0, 62), note_off(1, 61)]);
+    let sent = harness.run([note_on(0, 63), note_on(1, 64)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 63)]);
+}
+
+fn note_on_velocity(timing: u32, note: u8, velocity: f32) -> Event {
+    NoteEvent::NoteOn {
+        timing,
+        voice_id: None,
+        channel: 0,
+        note,
+        velocity,
+    }
+}
+
+#[test]
+fn forwarded_velocity_has_a_floor() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        forward_out_of_range_notes: on(),
+        max_note: int(0),
+        forward_velocity_floor: float(0.3),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on_velocity(0, 60, 0.1), note_on_velocity(1, 61, 0.8)]);
+    let velocities: Vec<_> = sent
+        .iter()
+        .filter_map(|event| match *event {
+            NoteEvent::NoteOn { velocity, .. } => Some(velocity),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(velocities, [0.3, 0.8]);
+}
+
+#[test]
+fn top_note_drives_the_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        track_top_note: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_on(1, 64), note_on(2, 62)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (1, 0, 64)]);
+    // Releasing the top note hands over to the next highest
+    assert_eq!(
+        program_changes(&harness.run([note_off(0, 64)])),
+        [(0, 0, 62)]
+    );
+    assert_eq!(program_changes(&harness.run([note_off(0, 60)])), []);
+    assert_eq!(program_changes(&harness.run([note_off(0, 62)])), []);
+}
+
+#[test]
+fn disarm_and_arm_notes_gate_conversion() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        arm_note: int(24),
+        disarm_note: int(25),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 25), note_on(1, 60)]);
+    assert_eq!(program_changes(&sent), []);
+    // Disarmed notes just play, the arm and disarm notes never do
+    assert_eq!(note_ons(&sent), [(1, 0, 60)]);
+    let sent = harness.run([note_on(0, 24), note_on(1, 61)]);
+    assert_eq!(program_changes(&sent), [(1, 0, 61)]);
+    assert_eq!(note_ons(&sent), []);
+}
+
+#[test]
+fn a_shared_arm_and_disarm_note_toggles() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        arm_note: int(24),
+        disarm_note: int(24),
+        ..Default::default()
+    });
+
+    let sent = harness.run([
+        note_on(0, 24),
+        note_on(1, 60),
+        note_on(2, 24),
+        note_on(3, 61),
+    ]);
+    assert_eq!(program_changes(&sent), [(3, 0, 61)]);
+}
+
+#[test]
+fn program_map_remaps_the_computed_program() {
+    let harness_with = |map: Vec<u8>| {
+        let harness = Harness::new(MidiNoteToPcParams::default());
+        *harness.plugin.params.program_map.write().unwrap() = map;
+        harness
+    };
+
+    let mut harness = harness_with((0..128).rev().collect());
+    let sent = harness.run([note_on(0, 0), note_on(1, 60)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 127), (1, 0, 67)]);
+    // Programs past the end of a short map stay as they are
+    let mut harness = harness_with(vec![5, 6]);
+    let sent = harness.run([note_on(0, 1), note_on(1, 60)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 6), (1, 0, 60)]);
+}
+
+#[test]
+fn split_mode_also_plays_converted_notes() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        split_note_channel: int(3),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_off(5, 60)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60)]);
+    assert_eq!(note_ons(&sent), [(0, 2, 60)]);
+    assert_eq!(note_offs(&sent), [(5, 2, 60)]);
+}
+
+#[test]
+fn split_notes_play_after_their_program_change() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        split_note_channel: int(3),
+        pc_lead_samples: int(10),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_off(60, 60)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60)]);
+    assert_eq!(note_ons(&sent), [(10, 2, 60)]);
+    // The note-off keeps the same distance, in the next buffer
+    assert_eq!(note_offs(&harness.run([])), [(6, 2, 60)]);
+}
+
+#[test]
+fn split_note_off_follows_its_note_on_channel() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        split_note_channel: int(3),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 60)]);
+    harness.plugin.params = Arc::new(MidiNoteToPcParams {
+        split_note_channel: int(4),
+        ..Default::default()
+    });
+    assert_eq!(note_offs(&harness.run([note_off(0, 60)])), [(0, 2, 60)]);
+}
+
+#[test]
+fn a_new_note_takes_over_the_resend_timer() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        resend_interval_ms: float(100.0),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_on(50, 61)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (50, 0, 61)]);
+    assert_eq!(program_changes(&harness.run([])), []);
+    assert_eq!(program_changes(&harness.run([])), [(22, 0, 61)]);
+    // Releasing the earlier note doesn't stop it
+    let sent = harness.run([note_off(0, 60)]);
+    assert_eq!(program_changes(&sent), [(58, 0, 61)]);
+}
+
+#[test]
+fn note_cc_follows_the_program_change() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        note_cc: int(20),
+        note_cc_scale: FloatParam::new(
+            "Test",
+            0.5,
+            FloatRange::Linear {
+                min: -4.0,
+                max: 4.0,
+            },
+        ),
+        note_cc_offset: int(10),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(3, 60)]);
+    let expected = [
+        NoteEvent::MidiProgramChange {
+            timing: 3,
+            channel: 0,
+            program: 60,
+        },
+        NoteEvent::MidiCC {
+            timing: 3,
+            channel: 0,
+            cc: 20,
+            value: 40.0 / 127.0,
+        },
+    ];
+    assert_eq!(sent, expected);
+}
+
+#[test]
+fn converted_notes_are_not_forwarded() {
+    let mut harness = Harness::new(MidiNoteToPcParams::default());
+
+    let sent = harness.run([note_on(0, 60), note_off(5, 60)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60)]);
+    assert_eq!(note_ons(&sent), []);
+    assert_eq!(note_offs(&sent), []);
+}
+
+#[test]
+fn saved_program_is_recalled_on_load() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        recall_program_on_load: on(),
+        last_program: AtomicU32::new(42),
+        last_channel: AtomicU32::new(3),
+        ..Default::default()
+    });
+    harness.plugin.recall_pending = true;
+
+    assert_eq!(program_changes(&harness.run([])), [(0, 3, 42)]);
+    assert_eq!(program_changes(&harness.run([])), []);
+}
+
+#[test]
+fn nothing_is_recalled_without_a_saved_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        recall_program_on_load: on(),
+        ..Default::default()
+    });
+    harness.plugin.recall_pending = true;
+
+    assert_eq!(program_changes(&harness.run([])), []);
+}
+
+/// Steps the program down with the command channel from program 0.
+fn step_down_from_0(out_of_range_program: OutOfRangeProgram) -> Vec<(u32, u8, u8)> {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        command_channel: int(16),
+        out_of_range_program: EnumParam::new("Test", out_of_range_program),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 0)]);
+    program_changes(&harness.run([note_on_channel(0, 15, 48)]))
+}
+
+#[test]
+fn stepping_down_from_0_follows_the_out_of_range_policy() {
+    assert_eq!(step_down_from_0(OutOfRangeProgram::Wrap), [(0, 0, 127)]);
+    assert_eq!(step_down_from_0(OutOfRangeProgram::Clamp), [(0, 0, 0)]);
+    assert_eq!(step_down_from_0(OutOfRangeProgram::Skip), []);
+}
+
+#[test]
+fn muted_channels_get_no_program_changes() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        broadcast_channel: int(10),
+        muted_channels: std::array::from_fn(|channel| MutedChannelParams {
+            muted: BoolParam::new("Test", channel == 2 || channel == 9),
+        }),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on_channel(0, 2, 60), note_on_channel(1, 3, 61)]);
+    assert_eq!(program_changes(&sent), [(1, 3, 61)]);
+    // The muted program still counts as the current one
+    assert_eq!(harness.plugin.channel_programs[2], Some(60));
+}
+
+#[test]
+fn offset_off_program_follows_the_out_of_range_policy() {
+    let released = |out_of_range_program| {
+        let mut harness = Harness::new(MidiNoteToPcParams {
+            note_off_behavior: EnumParam::new("Test", NoteOffBehavior::OffsetProgram),
+            off_program_offset: int(40),
+            out_of_range_program: EnumParam::new("Test", out_of_range_program),
+            ..Default::default()
+        });
+        harness.run([note_on(0, 90)]);
+        program_changes(&harness.run([note_off(5, 90)]))
+    };
+
+    assert_eq!(released(OutOfRangeProgram::Skip), []);
+    assert_eq!(released(OutOfRangeProgram::Clamp), [(5, 0, 127)]);
+    assert_eq!(released(OutOfRangeProgram::Wrap), [(5, 0, 2)]);
+}
+
+#[test]
+fn frozen_program_changes_are_held_back() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        freeze: on(),
+        recall_program_on_load: on(),
+        last_program: AtomicU32::new(42),
+        ..Default::default()
+    });
+    harness.plugin.recall_pending = true;
+
+    assert_eq!(program_changes(&harness.run([note_on(0, 60)])), []);
+    // Everything that tracks the current program keeps up
+    assert_eq!(harness.plugin.last_pc, Some(ProgramChange::new(0, 60)));
+}
+
+#[test]
+fn unfreezing_picks_up_the_held_back_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        freeze: on(),
+        dedupe_beats: float(2.0),
+        ..Default::default()
+    });
+
+    let sent = harness.run_with_grid([note_on(0, 60)], Some(grid(0)));
+    assert_eq!(program_changes(&sent), []);
+    let last_program = harness.plugin.params.last_program.load(Ordering::Relaxed);
+    assert_eq!(last_program, 60);
+    assert_eq!(harness.plugin.channel_programs[0], Some(60));
+
+    // The held-back program is still the current one once unfrozen, so
+    // repeating it within the window is dropped
+    harness.plugin.params = Arc::new(MidiNoteToPcParams {
+        dedupe_beats: float(2.0),
+        last_program: AtomicU32::new(last_program),
+        ..Default::default()
+    });
+    let sent = harness.run_with_grid([note_on(0, 60), note_on(10, 61)], Some(grid(1)));
+    assert_eq!(program_changes(&sent), [(10, 0, 61)]);
+    assert_eq!(harness.plugin.params.last_program.load(Ordering::Relaxed), 61);
+}
+
+#[test]
+fn a_busy_program_map_keeps_the_previous_copy() {
+    let mut harness = Harness::new(MidiNoteToPcParams::default());
+    let params = harness.plugin.params.clone();
+    *params.program_map.write().unwrap() = vec![10, 11];
+    assert_eq!(program_changes(&harness.run([note_on(0, 1)])), [(0, 0, 11)]);
+
+    // A writer holding the lock doesn't block the audio thread, which
+    // converts against the copy from the last buffer
+    let mut map = params.program_map.write().unwrap();
+    *map = vec![20, 21];
+    assert_eq!(program_changes(&harness.run([note_on(0, 0)])), [(0, 0, 10)]);
+    drop(map);
+    assert_eq!(program_changes(&harness.run([note_on(0, 1)])), [(0, 0, 21)]);
+}
+
+fn pressure(timing: u32, pressure: f32) -> Event {
+    NoteEvent::MidiChannelPressure {
+        timing,
+        channel: 0,
+        pressure,
+    }
+}
+
+#[test]
+fn aftertouch_steps_the_held_note_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        pressure_selects_program: on(),
+        pressure_program_steps: int(4),
+        ..Default::default()
+    });
+
+    assert_eq!(
+        program_changes(&harness.run([note_on(0, 60)])),
+        [(0, 0, 60)]
+    );
+    assert_eq!(
+        program_changes(&harness.run([pressure(1, 0.3)])),
+        [(1, 0, 61)]
+    );
+    // Staying in the same band sends nothing
+    assert_eq!(program_changes(&harness.run([pressure(2, 0.4)])), []);
+    assert_eq!(
+        program_changes(&harness.run([pressure(3, 1.0)])),
+        [(3, 0, 63)]
+    );
+    assert_eq!(
+        program_changes(&harness.run([pressure(4, 0.0)])),
+        [(4, 0, 60)]
+    );
+}
+
+#[test]
+fn double_press_confirms_the_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        require_double_press: on(),
+        double_press_ms: float(50.0),
+        ..Default::default()
+    });
+
+    assert_eq!(program_changes(&harness.run([note_on(0, 60)])), []);
+    // Too late for the first press, but it starts a new pair
+    let sent = harness.run([note_on(0, 60), note_off(5, 60), note_on(10, 60)]);
+    assert_eq!(program_changes(&sent), [(10, 0, 60)]);
+    // A third press starts over
+    assert_eq!(program_changes(&harness.run([note_on(0, 60)])), []);
+}
+
+#[test]
+fn unconfirmed_presses_release_to_nothing() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        require_double_press: on(),
+        note_off_behavior: EnumParam::new("Test", NoteOffBehavior::SendOffProgram),
+        off_program: int(100),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_off(5, 60)]);
+    assert_eq!(program_changes(&sent), []);
+    let sent = harness.run([note_on(0, 60), note_off(5, 60)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (5, 0, 100)]);
+}
+
+#[test]
+fn repeated_notes_release_to_nothing_in_once_per_note_mode() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        once_per_note: on(),
+        note_off_behavior: EnumParam::new("Test", NoteOffBehavior::SendOffProgram),
+        off_program: int(100),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_off(5, 60)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (5, 0, 100)]);
+    let sent = harness.run([note_on(0, 60), note_off(5, 60)]);
+    assert_eq!(program_changes(&sent), []);
+}
+
+#[test]
+fn sequential_programs_advance_with_any_note() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        sequential_programs: on(),
+        sequence_max: int(2),
+        ..Default::default()
+    });
+
+    let sent = harness.run([
+        note_on(0, 60),
+        note_on(1, 10),
+        note_on(2, 90),
+        note_on(3, 60),
+    ]);
+    assert_eq!(
+        program_changes(&sent),
+        [(0, 0, 0), (1, 0, 1), (2, 0, 2), (3, 0, 0)]
+    );
+    assert_eq!(
+        harness.plugin.params.sequence_index.load(Ordering::Relaxed),
+        1
+    );
+}
+
+#[test]
+fn sequential_programs_fit_the_device_patch_count() {
+    let fitted = |out_of_range_program| {
+        let mut harness = Harness::new(MidiNoteToPcParams {
+            sequential_programs: on(),
+            sequence_max: int(3),
+            device_patch_count: int(2),
+            out_of_range_program: EnumParam::new("Test", out_of_range_program),
+            ..Default::default()
+        });
+        // The note itself converts to program 0, so only the sequence can
+        // go past the device's patches
+        program_changes(&harness.run([
+            note_on(0, 0),
+            note_off(1, 0),
+            note_on(2, 0),
+            note_off(3, 0),
+            note_on(4, 0),
+            note_off(5, 0),
+            note_on(6, 0),
+        ]))
+    };
+
+    assert_eq!(fitted(OutOfRangeProgram::Skip), [(0, 0, 0), (2, 0, 1)]);
+    assert_eq!(
+        fitted(OutOfRangeProgram::Wrap),
+        [(0, 0, 0), (2, 0, 1), (4, 0, 0), (6, 0, 1)]
+    );
+}
+
+#[test]
+fn one_program_change_per_beat() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        one_per_beat: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run_with_grid([note_on(0, 60), note_on(30, 61)], Some(grid(0)));
+    assert_eq!(program_changes(&sent), [(0, 0, 60)]);
+    // Sample 40 of the second buffer is in the next beat
+    let sent = harness.run_with_grid([note_on(10, 62), note_on(40, 63)], Some(grid(1)));
+    assert_eq!(program_changes(&sent), [(40, 0, 63)]);
+}
+
+#[test]
+fn note_map_overrides_the_channel_and_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams::default());
+    *harness.plugin.params.note_map.write().unwrap() = vec![(36, 10, 5), (38, 0, 7)];
+
+    let sent = harness.run([note_on(0, 36), note_on(1, 38), note_on(2, 60)]);
+    assert_eq!(program_changes(&sent), [(0, 9, 5), (1, 0, 7), (2, 0, 60)]);
+}
+
+#[test]
+fn reset_all_controllers_goes_before_the_note_cc() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        reset_controllers_after_pc: on(),
+        note_cc: int(20),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(3, 60)]);
+    assert_eq!(program_changes(&sent), [(3, 0, 60)]);
+    assert!(matches!(sent[0], NoteEvent::MidiProgramChange { .. }));
+    assert_eq!(ccs(&sent[1..]), [(3, 0, 121, 0), (3, 0, 20, 60)]);
+}
+
+#[test]
+fn hammered_notes_keep_the_minimum_interval() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        min_channel_interval_ms: float(20.0),
+        ..Default::default()
+    });
+
+    let mut sent = Vec::new();
+    let mut note = 0;
+    // A note every 3 ms for four buffers, then one to let the last out
+    for buffer in 0..5 {
+        let notes: Vec<_> = (0..BUFFER as u32)
+            .step_by(3)
+            .take_while(|_| buffer < 4)
+            .map(|timing| {
+                note = (note + 1) % 90;
+                note_on(timing, note)
+            })
+            .collect();
+        let offset = (buffer * BUFFER) as u32;
+        sent.extend(
+            program_changes(&harness.run(notes))
+                .into_iter()
+                .map(|(timing, _, program)| (offset + timing, program)),
+        );
+    }
+
+    assert!(sent.windows(2).all(|pair| pair[1].0 - pair[0].0 >= 20));
+    // The last held-back note isn't lost
+    assert_eq!(sent.last().map(|&(_, program)| program), Some(note));
+}
+
+#[test]
+fn dropped_changes_dont_count_towards_the_limits() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        min_channel_interval_ms: float(100.0),
+        dedupe_beats: float(4.0),
+        one_per_beat: on(),
+        ..Default::default()
+    });
+
+    assert_eq!(
+        program_changes(&harness.run_with_grid([note_on(0, 60)], Some(grid(0)))),
+        [(0, 0, 60)]
+    );
+    // The repeat at sample 110 is dropped, so it neither uses up beat 1
+    // nor restarts the interval
+    let sent = harness.run_with_grid([note_on(46, 60)], Some(grid(1)));
+    assert_eq!(program_changes(&sent), []);
+    let sent = harness.run_with_grid([note_on(22, 61)], Some(grid(2)));
+    assert_eq!(program_changes(&sent), [(22, 0, 61)]);
+}
+
+#[test]
+fn program_cc_replaces_the_program_change() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        program_cc: int(70),
+        broadcast_channel: int(5),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(3, 60)]);
+    assert_eq!(program_changes(&sent), []);
+    assert_eq!(ccs(&sent), [(3, 0, 70, 60), (3, 4, 70, 60)]);
+}
+
+#[cfg(feature = "logging")]
+#[test]
+fn sent_program_changes_are_logged() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        muted_channels: std::array::from_fn(|channel| MutedChannelParams {
+            muted: BoolParam::new("Test", channel == 1),
+        }),
+        ..Default::default()
+    });
+
+    harness.run([]);
+    harness.run([note_on(36, 60), note_on_channel(40, 1, 61)]);
+    let logged: Vec<_> = harness
+        .context
+        .background_tasks
+        .take()
+        .into_iter()
+        .map(|task| match task {
+            program_log::Task::Log(entry) => (entry.seconds, entry.channel, entry.program),
+            task => panic!("expected a log entry, got {task:?}"),
+        })
+        .collect();
+    // 100 samples in, and nothing for the muted channel
+    assert_eq!(logged, [(0.1, 0, 60)]);
+}
+
+#[cfg(feature = "logging")]
+#[test]
+fn an_f_on_the_command_channel_exports_the_session() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        command_channel: int(16),
+        ..Default::default()
+    });
+
+    harness.run_with_grid([note_on(0, 60)], Some(grid(0)));
+    harness.run([note_on_channel(0, 15, 53)]);
+    let tasks = harness.context.background_tasks.take();
+    let [program_log::Task::Log(entry), program_log::Task::Export] = tasks[..] else {
+        panic!("expected a log entry and an export, got {tasks:?}");
+    };
+    // 100 samples per beat at 1 kHz is 600 BPM, 100000 µs per beat
+    assert_eq!(entry.tempo, Some(600.0));
+    let file = smf::encode(&[entry]);
+    assert_eq!(
+        file[22..],
+        [0x00, 0xff, 0x51, 0x03, 0x01, 0x86, 0xa0, 0x00, 0xc0, 60, 0x00, 0xff, 0x2f, 0x00]
+    );
+}
+
+#[test]
+fn same_timing_order_decides_the_final_program() {
+    let legato = |same_timing_order| {
+        let mut harness = Harness::new(MidiNoteToPcParams {
+            same_timing_order: EnumParam::new("Test", same_timing_order),
+            note_off_behavior: EnumParam::new("Test", NoteOffBehavior::RevertDefault),
+            ..Default::default()
+        });
+        harness.run([note_on(0, 60)]);
+        program_changes(&harness.run([note_on(5, 61), note_off(5, 60)]))
+    };
+
+    assert_eq!(legato(SameTimingOrder::AsReceived), [(5, 0, 61), (5, 0, 0)]);
+    assert_eq!(
+        legato(SameTimingOrder::NoteOffsFirst),
+        [(5, 0, 0), (5, 0, 61)]
+    );
+    assert_eq!(
+        legato(SameTimingOrder::NoteOnsFirst),
+        [(5, 0, 61), (5, 0, 0)]
+    );
+}
+
+#[test]
+fn same_timing_order_sorts_the_whole_run() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        same_timing_order: EnumParam::new("Test", SameTimingOrder::NoteOffsFirst),
+        note_off_behavior: EnumParam::new("Test", NoteOffBehavior::RevertDefault),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 60)]);
+    let sent = harness.run([
+        note_on(5, 61),
+        note_on(5, 62),
+        note_off(5, 60),
+        note_on(6, 63),
+    ]);
+    // The note-ons keep their order behind the note-off
+    assert_eq!(
+        program_changes(&sent),
+        [(5, 0, 0), (5, 0, 61), (5, 0, 62), (6, 0, 63)]
+    );
+}
+
+#[test]
+fn notes_on_the_reserved_channel_pass_through() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        passthrough_channel_for_notes: int(2),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on_channel(0, 1, 60), note_on(1, 61)]);
+    assert_eq!(program_changes(&sent), [(1, 0, 61)]);
+    assert_eq!(note_ons(&sent), [(0, 1, 60)]);
+}
+
+#[test]
+fn loudest_note_in_the_coalesce_window_wins() {
+    let chord = |notes: [(u8, f32); 3]| {
+        let mut harness = Harness::new(MidiNoteToPcParams {
+            coalesce_ms: float(20.0),
+            coalesce_pick: EnumParam::new("Test", CoalescePick::Loudest),
+            ..Default::default()
+        });
+        let notes = notes
+            .into_iter()
+            .enumerate()
+            .map(|(timing, (note, velocity))| note_on_velocity(timing as u32, note, velocity));
+        program_changes(&harness.run(notes))
+    };
+
+    assert_eq!(chord([(60, 0.5), (64, 0.9), (67, 0.7)]), [(20, 0, 64)]);
+    // A tie goes to the lower note, whatever the order
+    assert_eq!(chord([(67, 0.8), (60, 0.5), (64, 0.8)]), [(20, 0, 64)]);
+    assert_eq!(chord([(64, 0.8), (67, 0.8), (60, 0.5)]), [(20, 0, 64)]);
+}
+
+#[test]
+fn every_channel_program_is_saved_and_recalled() {
+    let mut harness = Harness::new(MidiNoteToPcParams::default());
+    harness.run([note_on_channel(0, 4, 60), note_on_channel(1, 1, 30)]);
+    let saved = *harness.plugin.params.channel_programs.read().unwrap();
+    let mut expected = [None; 16];
+    expected[1] = Some(30);
+    expected[4] = Some(60);
+    assert_eq!(saved, expected);
+
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        recall_all_channels_on_load: on(),
+        recall_program_on_load: on(),
+        last_program: AtomicU32::new(42),
+        ..Default::default()
+    });
+    harness.plugin.channel_programs = saved;
+    harness.plugin.recall_pending = true;
+    // In channel order, instead of the single last program
+    assert_eq!(program_changes(&harness.run([])), [(0, 1, 30), (0, 4, 60)]);
+}
+
+#[test]
+fn learned_range_is_spread_over_every_program() {
+    // Flips the toggles, keeping the persisted range like the host would
+    let set = |harness: &mut Harness, learn_range, use_learned_range| {
+        let learned_range = harness.plugin.params.learned_range.load(Ordering::Relaxed);
+        harness.plugin.params = Arc::new(MidiNoteToPcParams {
+            learn_range,
+            use_learned_range,
+            learned_range: AtomicU32::new(learned_range),
+            ..Default::default()
+        });
+    };
+    let mut harness = Harness::new(MidiNoteToPcParams::default());
+    set(&mut harness, on(), on());
+    harness.run([
+        note_on(0, 52),
+        note_on(1, 40),
+        note_on(2, 64),
+        note_on(3, 45),
+    ]);
+    assert_eq!(harness.plugin.learned_range(), Some((40, 64)));
+
+    set(&mut harness, off(), on());
+    let sent = harness.run([note_on(0, 40), note_on(1, 64), note_on(2, 65)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 0), (1, 0, 127)]);
+
+    // Switched off, the note range is back and the learned one is kept
+    set(&mut harness, off(), off());
+    let sent = harness.run([note_on(0, 40), note_on(1, 65)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 40), (1, 0, 65)]);
+    assert_eq!(harness.plugin.learned_range(), Some((40, 64)));
+}
+
+#[test]
+fn learning_records_the_quantized_note() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        learn_range: on(),
+        note_quantize_interval: int(12),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 41), note_on(1, 63)]);
+    // What the range gate sees, 36 and 60
+    assert_eq!(harness.plugin.learned_range(), Some((36, 60)));
+}
+
+#[test]
+fn pedal_release_commits_the_latest_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        commit_on_pedal_release: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([cc(0, 64, 127), note_on(1, 60), note_on(2, 62)]);
+    assert_eq!(program_changes(&sent), []);
+    // Still down
+    assert_eq!(program_changes(&harness.run([cc(0, 64, 100)])), []);
+    assert_eq!(program_changes(&harness.run([cc(9, 64, 0)])), [(9, 0, 62)]);
+    assert_eq!(program_changes(&harness.run([cc(0, 64, 0)])), []);
+}
+
+#[test]
+fn late_timings_are_clamped_to_the_last_sample() {
+    let last_sample = BUFFER as u32 - 1;
+    assert_eq!(clamp_timing(note_on(70, 60), last_sample), note_on(63, 60));
+    assert_eq!(clamp_timing(cc(64, 1, 10), last_sample), cc(63, 1, 10));
+    assert_eq!(
+        clamp_timing(note_off(63, 60), last_sample),
+        note_off(63, 60)
+    );
+    assert_eq!(
+        clamp_timing(pressure(5, 0.5), last_sample),
+        pressure(5, 0.5)
+    );
+}
+
+#[test]
+fn velocity_picks_the_bank_lsb() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        velocity_bank_lsb: on(),
+        velocity_bank_low: int(4),
+        velocity_bank_high: int(7),
+        ..Default::default()
+    });
+
+    let sent = harness.run([
+        note_on_velocity(0, 60, 0.0),
+        note_on_velocity(1, 61, 0.5),
+        note_on_velocity(2, 62, 1.0),
+    ]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (1, 0, 61), (2, 0, 62)]);
+    assert_eq!(ccs(&sent), [(0, 0, 32, 4), (1, 0, 32, 6), (2, 0, 32, 7)]);
+}
+
+#[test]
+fn incoming_program_changes_gain_a_bank_select() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        expand_program_changes: on(),
+        expand_bank_msb: int(2),
+        ..Default::default()
+    });
+
+    let sent = harness.run([NoteEvent::MidiProgramChange {
+        timing: 4,
+        channel: 2,
+        program: 5,
+    }]);
+    assert_eq!(program_changes(&sent), [(4, 2, 5)]);
+    // No LSB while it's -1
+    assert_eq!(ccs(&sent), [(4, 2, 0, 2)]);
+    assert!(matches!(sent[1], NoteEvent::MidiProgramChange { .. }));
+}
+
+#[test]
+fn stepping_resumes_from_the_saved_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        command_channel: int(16),
+        last_program: AtomicU32::new(42),
+        last_channel: AtomicU32::new(3),
+        ..Default::default()
+    });
+
+    assert_eq!(
+        program_changes(&harness.run([note_on_channel(0, 15, 50)])),
+        [(0, 3, 43)]
+    );
+    assert_eq!(
+        program_changes(&harness.run([note_on_channel(0, 15, 50)])),
+        [(0, 3, 44)]
+    );
+}
+
+#[test]
+fn spread_cycles_repeated_programs_among_neighbours() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        spread: int(1),
+        ..Default::default()
+    });
+    let mut repeat = |note| -> Vec<u8> {
+        (0..4)
+            .flat_map(|_| program_changes(&harness.run([note_on(0, note)])))
+            .map(|(_, _, program)| program)
+            .collect()
+    };
+
+    assert_eq!(repeat(60), [60, 61, 59, 60]);
+    // A neighbour that is skipped as out of range leaves the program as is
+    assert_eq!(repeat(0), [0, 1, 0, 0]);
+}
+
+#[test]
+fn per_phrase_follow_stays_on_the_first_channel() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        follow_mode: EnumParam::new("Test", FollowMode::PerPhrase),
+        ..Default::default()
+    });
+
+    let sent = harness.run([
+        note_on_channel(0, 2, 60),
+        note_on_channel(1, 5, 61),
+        note_off_channel(2, 2, 60),
+        note_on_channel(3, 5, 62),
+    ]);
+    assert_eq!(program_changes(&sent), [(0, 2, 60), (1, 2, 61), (3, 2, 62)]);
+    // Once everything is released the next note starts a new phrase
+    let sent = harness.run([
+        note_off_channel(0, 5, 61),
+        note_off_channel(1, 5, 62),
+        note_on_channel(2, 5, 63),
+    ]);
+    assert_eq!(program_changes(&sent), [(2, 5, 63)]);
+}
+
+#[test]
+fn per_phrase_follow_releases_on_the_phrase_channel() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        follow_mode: EnumParam::new("Test", FollowMode::PerPhrase),
+        note_off_behavior: EnumParam::new("Test", NoteOffBehavior::SendOffProgram),
+        off_program: int(100),
+        ..Default::default()
+    });
+
+    let sent = harness.run([
+        note_on_channel(0, 2, 60),
+        note_on_channel(1, 5, 61),
+        note_off_channel(2, 5, 61),
+        note_off_channel(3, 2, 60),
+    ]);
+    assert_eq!(
+        program_changes(&sent),
+        [(0, 2, 60), (1, 2, 61), (2, 2, 100), (3, 2, 100)]
+    );
+}
+
+#[test]
+fn idle_default_program_follows_the_last_release() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        idle_default_program: int(5),
+        idle_ms: float(100.0),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on_channel(10, 3, 60), note_off_channel(20, 3, 60)]);
+    assert_eq!(program_changes(&sent), [(10, 3, 60)]);
+    // 100 ms after the release, on the last program's channel, and only once
+    assert_eq!(program_changes(&harness.run([])), [(56, 3, 5)]);
+    assert_eq!(program_changes(&harness.run([])), []);
+    assert_eq!(program_changes(&harness.run([])), []);
+}
+
+#[test]
+fn expression_patch_carries_the_mod_wheel() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        expression_patch_sysex: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([cc(0, 1, 100), note_on(5, 60)]);
+    let patch = NoteEvent::MidiSysEx {
+        timing: 5,
+        message: OutputSysEx::ExpressionPatch(ExpressionPatch {
+            channel: 0,
+            program: 60,
+            mod_wheel: 100,
+        }),
+    };
+    assert_eq!(program_changes(&sent), []);
+    assert!(sent.contains(&patch));
+}
+
+#[test]
+fn note_name_follows_the_program_change() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        note_name_sysex: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(5, 61)]);
+    let name = NoteEvent::MidiSysEx {
+        timing: 5,
+        message: OutputSysEx::NoteName(NoteName { note: 61 }),
+    };
+    assert_eq!(program_changes(&sent), [(5, 0, 61)]);
+    assert_eq!(sent.last(), Some(&name));
+}
+
+#[test]
+fn repeated_programs_within_the_beat_window_are_dropped() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        dedupe_beats: float(2.0),
+        ..Default::default()
+    });
+    let mut play = |buffer, timing| {
+        program_changes(&harness.run_with_grid([note_on(timing, 60)], Some(grid(buffer))))
+    };
+
+    assert_eq!(play(0, 0), [(0, 0, 60)]);
+    // Beat 1, then beat 2.5
+    assert_eq!(play(1, 36), []);
+    assert_eq!(play(3, 58), [(58, 0, 60)]);
+    // Looping back never counts as a repeat
+    assert_eq!(play(0, 10), [(10, 0, 60)]);
+}
+
+#[test]
+fn debug_dump_reports_every_channel_program() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        debug_dump_note: int(120),
+        ..Default::default()
+    });
+
+    harness.run([
+        note_on_channel(0, 4, 60),
+        note_on_channel(1, 2, 30),
+        note_off_channel(2, 2, 30),
+    ]);
+    let sent = harness.run([note_on_channel(3, 7, 120)]);
+    assert_eq!(program_changes(&sent), []);
+    assert_eq!(
+        ccs(&sent),
+        [
+            (3, 7, DEBUG_HELD_NOTES_CC, 1),
+            (3, 2, DEBUG_PROGRAM_CC, 30),
+            (3, 4, DEBUG_PROGRAM_CC, 60),
+        ]
+    );
+}
+
+#[test]
+fn notes_only_convert_while_the_modifier_is_held() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        modifier_cc: int(80),
+        modifier_threshold: int(64),
+        ..Default::default()
+    });
+
+    let sent = harness.run([
+        note_on(0, 60),
+        cc(1, 80, 64),
+        note_on(2, 61),
+        cc(3, 80, 63),
+        note_on(4, 62),
+    ]);
+    assert_eq!(program_changes(&sent), [(2, 0, 61)]);
+    assert_eq!(note_ons(&sent), [(0, 0, 60), (4, 0, 62)]);
+}
+
+#[test]
+fn programs_are_only_sent_on_load_after_a_state_load() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        recall_program_on_load: on(),
+        last_program: AtomicU32::new(42),
+        send_fixed_program_on_load: on(),
+        fixed_program: int(7),
+        ..Default::default()
+    });
+
+    harness.initialize();
+    assert_eq!(program_changes(&harness.run([])), []);
+
+    // What the wrapper does for every saved persisted field
+    harness.plugin.params.state_loaded.set(true);
+    harness.initialize();
+    assert_eq!(program_changes(&harness.run([])), [(0, 0, 42), (0, 0, 7)]);
+
+    // Like a sample rate change
+    harness.initialize();
+    assert_eq!(program_changes(&harness.run([])), []);
+}
+
+#[test]
+fn program_changes_are_copied_to_the_monitor_channel() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        monitor_pc_channel: int(16),
+        monitor_pc_offset: int(50),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on(0, 60), note_on(1, 90)]);
+    // The offset wraps past 127
+    let expected = [(0, 0, 60), (0, 15, 110), (1, 0, 90), (1, 15, 12)];
+    assert_eq!(program_changes(&sent), expected);
+}
+
+#[test]
+fn muted_programs_are_not_monitored() {
+    let muted = |muted_channel: usize| {
+        let mut harness = Harness::new(MidiNoteToPcParams {
+            monitor_pc_channel: int(16),
+            muted_channels: std::array::from_fn(|channel| MutedChannelParams {
+                muted: BoolParam::new("Test", channel == muted_channel),
+            }),
+            ..Default::default()
+        });
+        program_changes(&harness.run([note_on_channel(0, 2, 60), note_on_channel(1, 3, 61)]))
+    };
+
+    // A muted source isn't copied, and a muted monitor gets nothing
+    assert_eq!(muted(2), [(1, 3, 61), (1, 15, 61)]);
+    assert_eq!(muted(15), [(0, 2, 60), (1, 3, 61)]);
+}
+
+#[test]
+fn queued_morphs_always_reach_their_target() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        morph_steps: on(),
+        morph_ms: float(10.0),
+        morph_interrupt: EnumParam::new("Test", MorphInterrupt::Queue),
+        ..Default::default()
+    });
+
+    harness.run([note_on(0, 0)]);
+    // Far more steps than the queue holds
+    let notes = [99, 0, 99, 0, 99, 0, 99].into_iter().enumerate();
+    let mut sent = harness.run(notes.map(|(timing, note)| note_on(timing as u32 + 1, note)));
+    while !harness.plugin.pending.is_empty() {
+        sent.extend(harness.run([]));
+    }
+
+    let programs: Vec<_> = program_changes(&sent)
+        .into_iter()
+        .map(|(_, _, program)| program)
+        .collect();
+    assert_eq!(programs.last(), Some(&99));
+    // The first morph still goes through every program
+    assert_eq!(programs[..99], *(1..=99).collect::<Vec<_>>());
+}
+
+#[test]
+fn velocity_picks_the_bank_msb() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        velocity_bank_msb: on(),
+        velocity_msb_low: int(10),
+        velocity_msb_high: int(14),
+        velocity_msb_lsb: int(5),
+        per_channel_bank: on(),
+        ..Default::default()
+    });
+
+    let sent = harness.run([note_on_velocity(0, 60, 0.5), note_on_velocity(1, 61, 1.0)]);
+    assert_eq!(program_changes(&sent), [(0, 0, 60), (1, 0, 61)]);
+    // The velocity's bank wins over the channel's
+    let expected = [(0, 0, 0, 12), (0, 0, 32, 5), (1, 0, 0, 14), (1, 0, 32, 5)];
+    assert_eq!(ccs(&sent), expected);
+}
+
+#[test]
+fn command_channel_steps_and_panics() {
+    let mut harness = Harness::new(MidiNoteToPcParams {
+        command_channel: int(16),
+        output_channel: int(3),
+        coalesce_ms: float(100.0),
+        ..Default::default()
+    });
+    let command = |note| note_on_channel(0, 15, note);
+
+    // Nothing sent or saved yet, so stepping starts from program 0
+    assert_eq!(program_changes(&harness.run([command(62)])), [(0, 2, 1)]);
+    assert_eq!(program_changes(&harness.run([command(14)])), [(0, 2, 2)]);
+    assert_eq!(program_changes(&harness.run([command(12)])), [(0, 2, 1)]);
+
+    // Panic drops the coalesced program and silences every channel
+    harness.run([note_on(0, 60)]);
+    let sent = harness.run([command(64)]);
+    assert_eq!(
+        ccs(&sent),
+        (0..16)
+            .map(|channel| (0, channel, 123, 0))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(program_changes(&harness.run([])), []);
+    assert_eq!(program_changes(&harness.run([])), []);
+}
@@ -0,0 +1,145 @@
+//! Musical time derived from the host transport, for the tempo-synced
+//! features.
+
+use nih_plug::prelude::Transport;
+
+/// Tolerance for treating a position as lying exactly on a grid line, so
+/// floating point error doesn't push an on-the-beat event to the next beat.
+const GRID_EPSILON: f64 = 1e-9;
+
+/// Where the current buffer sits in musical time. Positions are in quarter
+/// notes, like the host's PPQ position.
+#[derive(Debug, Clone, Copy)]
+pub struct TransportGrid {
+    /// Position at the first sample of the buffer.
+    pub start_beats: f64,
+    pub samples_per_beat: f64,
+    /// Start of the current bar, if the host reports it.
+    pub bar_start_beats: Option<f64>,
+    /// Bar length in quarter notes, from the host's time signature.
+    pub beats_per_bar: f64,
+}
+
+/// The bar length in quarter notes, e.g. 3 for 3/4 and 3.5 for 7/8. Falls
+/// back to 4/4 when the host doesn't report a (sensible) time signature.
+/// The grid is rebuilt every buffer, so time signature changes mid-song are
+/// picked up at the next buffer.
+fn beats_per_bar(numerator: Option<i32>, denominator: Option<i32>) -> f64 {
+    match (numerator, denominator) {
+        (Some(numerator), Some(denominator)) if numerator > 0 && denominator > 0 => {
+            numerator as f64 * 4.0 / denominator as f64
+        }
+        _ => 4.0,
+    }
+}
+
+impl TransportGrid {
+    /// Returns `None` while the transport is stopped or the host doesn't
+    /// report a tempo and position, since there is no grid to follow then.
+    pub fn new(transport: &Transport) -> Option<Self> {
+        if !transport.playing {
+            return None;
+        }
+
+        // A zero or garbage tempo would put every grid line at infinity
+        let tempo = transport.tempo.filter(|tempo| tempo.is_finite() && *tempo > 0.0)?;
+        Some(Self {
+            start_beats: transport.pos_beats().filter(|beats| beats.is_finite())?,
+            samples_per_beat: 60.0 / tempo * transport.sample_rate as f64,
+            bar_start_beats: transport.bar_start_pos_beats(),
+            beats_per_bar: beats_per_bar(
+                transport.time_sig_numerator,
+                transport.time_sig_denominator,
+            ),
+        })
+    }
+
+    /// The position of the sample at `timing` within the buffer.
+    pub fn beats_at(&self, timing: u32) -> f64 {
+        self.start_beats + timing as f64 / self.samples_per_beat
+    }
+
+    /// The number of the beat the sample at `timing` falls in, counting
+    /// from the start of the song.
+    pub fn beat_index(&self, timing: u32) -> i64 {
+        (self.beats_at(timing) + GRID_EPSILON).floor() as i64
+    }
+
+    /// Samples from the start of the buffer until `beats`, or 0 if that
+    /// position has already passed.
+    pub fn samples_until(&self, beats: f64) -> u64 {
+        ((beats - self.start_beats) * self.samples_per_beat)
+            .max(0.0)
+            .round() as u64
+    }
+
+    /// The first beat at or after `beats`.
+    pub fn next_beat(&self, beats: f64) -> f64 {
+        (beats - GRID_EPSILON).ceil()
+    }
+
+    /// The first bar line at or after `beats`.
+    pub fn next_bar(&self, beats: f64) -> f64 {
+        let origin = self.bar_start_beats.unwrap_or(0.0);
+        let bars = ((beats - origin) / self.beats_per_bar - GRID_EPSILON).ceil();
+
+        origin + bars * self.beats_per_bar
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(beats_per_bar: f64, bar_start_beats: Option<f64>) -> TransportGrid {
+        TransportGrid {
+            start_beats: 0.0,
+            samples_per_beat: 100.0,
+            bar_start_beats,
+            beats_per_bar,
+        }
+    }
+
+    #[test]
+    fn bar_length_follows_the_time_signature() {
+        assert_eq!(beats_per_bar(Some(3), Some(4)), 3.0);
+        assert_eq!(beats_per_bar(Some(6), Some(8)), 3.0);
+        assert_eq!(beats_per_bar(Some(7), Some(8)), 3.5);
+        assert_eq!(beats_per_bar(Some(5), Some(4)), 5.0);
+    }
+
+    #[test]
+    fn missing_or_bogus_time_signatures_fall_back_to_4_4() {
+        assert_eq!(beats_per_bar(None, None), 4.0);
+        assert_eq!(beats_per_bar(Some(3), None), 4.0);
+        assert_eq!(beats_per_bar(Some(0), Some(4)), 4.0);
+        assert_eq!(beats_per_bar(Some(3), Some(-4)), 4.0);
+    }
+
+    #[test]
+    fn bar_lines_in_3_4() {
+        let grid = grid(3.0, None);
+        assert_eq!(grid.next_bar(0.5), 3.0);
+        assert_eq!(grid.next_bar(3.0), 3.0);
+        assert_eq!(grid.next_bar(4.0), 6.0);
+        assert_eq!(grid.next_bar(8.9), 9.0);
+    }
+
+    #[test]
+    fn bar_lines_count_from_the_host_bar_start() {
+        // A 3/4 bar that started on beat 10, after a tempo or meter change
+        let grid = grid(3.0, Some(10.0));
+        assert_eq!(grid.next_bar(10.5), 13.0);
+        assert_eq!(grid.next_bar(13.0), 13.0);
+        assert_eq!(grid.next_bar(14.0), 16.0);
+    }
+
+    #[test]
+    fn beat_lines_tolerate_rounding_error() {
+        let grid = grid(4.0, None);
+        assert_eq!(grid.next_beat(2.0 - 1e-12), 2.0);
+        assert_eq!(grid.next_beat(2.0 + 1e-12), 2.0);
+        assert_eq!(grid.beat_index(199), 1);
+        assert_eq!(grid.samples_until(2.5), 250);
+    }
+}